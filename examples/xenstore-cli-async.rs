@@ -1,6 +1,12 @@
+use std::time::Duration;
+
 use clap::{Parser, Subcommand};
 use futures::StreamExt;
-use xenstore_rs::{tokio::XsTokio, AsyncWatch, AsyncXs};
+use xenstore_rs::{
+    control::{self, LiveUpdate, LiveUpdateImage},
+    tokio::XsTokio,
+    AsyncWatch, AsyncXs, AsyncXsControl,
+};
 
 /// Demo/test tool for xenstore Rust bindings
 #[derive(Parser)]
@@ -26,6 +32,11 @@ enum Command {
         #[arg()]
         path: String,
     },
+    /// Create Xenstore directory (and its parents)
+    Mkdir {
+        #[arg()]
+        path: String,
+    },
     /// Write value to Xenstore path
     Write {
         #[arg()]
@@ -38,6 +49,77 @@ enum Command {
         #[arg()]
         path: String,
     },
+    /// Run a xenstored control command
+    Control {
+        #[command(subcommand)]
+        command: ControlCommand,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(disable_help_subcommand = true)]
+enum ControlCommand {
+    /// Enable or disable xenstored logging
+    Log {
+        #[arg(value_parser = ["on", "off"])]
+        state: String,
+    },
+    /// Set xenstored log file
+    Logfile {
+        #[arg()]
+        file: String,
+    },
+    /// Show xenstored memory usage (or write it to a file)
+    Memreport {
+        #[arg()]
+        file: Option<String>,
+    },
+    /// Show global hard quotas (or the usage of a domain)
+    Quota {
+        #[arg(long)]
+        domid: Option<u16>,
+    },
+    /// Set a global hard quota
+    QuotaSet {
+        #[arg()]
+        name: String,
+        #[arg()]
+        value: u32,
+    },
+    /// Show global soft quotas
+    QuotaSoft,
+    /// Set a global soft quota
+    QuotaSoftSet {
+        #[arg()]
+        name: String,
+        #[arg()]
+        value: u32,
+    },
+    /// Print a message in xenstored log
+    Print {
+        #[arg()]
+        message: String,
+    },
+    /// List xenstored control commands
+    Help,
+    /// Live update xenstored
+    LiveUpdate {
+        /// New xenstored binary (path as seen by xenstored unless --binary)
+        #[arg()]
+        file: String,
+        /// Upload the binary to xenstored (e.g xenstore stubdomain)
+        #[arg(long)]
+        binary: bool,
+        /// Command line of the new xenstored
+        #[arg(short, long)]
+        cmdline: Option<String>,
+        /// Seconds to wait for xenstored to be quiescent
+        #[arg(short, long)]
+        timeout: Option<u64>,
+        /// Update even with pending transactions
+        #[arg(short = 'F', long)]
+        force: bool,
+    },
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -51,37 +133,109 @@ async fn main() {
         Command::List { path } => cmd_list(&mut xs, &path).await,
         Command::Read { path } => cmd_read(&mut xs, &path).await,
         Command::Rm { path } => cmd_rm(&mut xs, &path).await,
+        Command::Mkdir { path } => cmd_mkdir(&mut xs, &path).await,
         Command::Write { path, data } => cmd_write(&mut xs, &path, &data).await,
         Command::Watch { path } => cmd_watch(&mut xs, &path).await,
+        Command::Control { command } => cmd_control(&xs, command).await,
     }
 }
 
-async fn cmd_list(xs: &mut impl AsyncXs, path: &String) {
-    let values = xs.directory(&path).await.expect("path should be readable");
+async fn cmd_list(xs: &mut impl AsyncXs, path: &str) {
+    let values = xs.directory(path).await.expect("path should be readable");
     for value in values {
         println!("{}", value);
     }
 }
 
-async fn cmd_read(xs: &mut impl AsyncXs, path: &String) {
-    let value = xs.read(&path).await.expect("path should be readable");
+async fn cmd_read(xs: &mut impl AsyncXs, path: &str) {
+    let value = xs.read(path).await.expect("path should be readable");
     println!("{}", value);
 }
 
-async fn cmd_rm(xs: &mut impl AsyncXs, path: &String) {
-    xs.rm(&path).await.expect("cannot rm xenstore path");
+async fn cmd_rm(xs: &mut impl AsyncXs, path: &str) {
+    xs.rm(path).await.expect("cannot rm xenstore path");
+}
+
+async fn cmd_mkdir(xs: &mut impl AsyncXs, path: &str) {
+    xs.mkdir(path)
+        .await
+        .expect("cannot create xenstore directory");
 }
 
-async fn cmd_write(xs: &mut impl AsyncXs, path: &String, data: &String) {
-    xs.write(&path, &data)
+async fn cmd_write(xs: &mut impl AsyncXs, path: &str, data: &str) {
+    xs.write(path, data)
         .await
         .expect("cannot write to xenstore path");
 }
 
-async fn cmd_watch<XS: AsyncXs + AsyncWatch>(xs: &mut XS, path: &String) {
-    let mut stream = xs.watch(&path).await.expect("path should be watchable");
+async fn cmd_watch<XS: AsyncXs + AsyncWatch>(xs: &mut XS, path: &str) {
+    let mut stream = xs.watch(path).await.expect("path should be watchable");
 
     while let Some(entry) = stream.next().await {
         println!("{entry}: {:?}", xs.read(&entry).await);
     }
 }
+
+async fn cmd_control(xs: &XsTokio, command: ControlCommand) {
+    match command {
+        ControlCommand::Log { state } => xs.control(&control::Log(state == "on")).await,
+        ControlCommand::Logfile { file } => xs.control(&control::Logfile(&file)).await,
+        ControlCommand::Memreport { file: Some(file) } => {
+            xs.control(&control::MemreportToFile(&file)).await
+        }
+        ControlCommand::Memreport { file: None } => xs
+            .control(&control::Memreport)
+            .await
+            .map(|report| println!("{report}")),
+        ControlCommand::Quota { domid } => xs
+            .control(&control::Quota { domid })
+            .await
+            .map(print_quotas),
+        ControlCommand::QuotaSet { name, value } => {
+            xs.control(&control::QuotaSet { name: &name, value }).await
+        }
+        ControlCommand::QuotaSoft => xs.control(&control::QuotaSoft).await.map(print_quotas),
+        ControlCommand::QuotaSoftSet { name, value } => {
+            xs.control(&control::QuotaSoftSet { name: &name, value })
+                .await
+        }
+        ControlCommand::Print { message } => xs.control(&control::Print(&message)).await,
+        ControlCommand::Help => xs.control(&control::Help).await.map(|entries| {
+            for entry in entries {
+                let usage = entry.usage.replace('\n', &format!("\n{:16}", ""));
+                println!("{:<16}{usage}", entry.command);
+            }
+        }),
+        ControlCommand::LiveUpdate {
+            file,
+            binary,
+            cmdline,
+            timeout,
+            force,
+        } => {
+            let data;
+            let image = if binary {
+                data = std::fs::read(&file).expect("binary should be readable");
+                LiveUpdateImage::Binary(&data)
+            } else {
+                LiveUpdateImage::File(&file)
+            };
+
+            let update = LiveUpdate {
+                cmdline: cmdline.as_deref(),
+                timeout: timeout.map(Duration::from_secs),
+                force,
+                ..LiveUpdate::new(image)
+            };
+
+            xs.live_update(&update).await
+        }
+    }
+    .expect("control command should succeed");
+}
+
+fn print_quotas(quotas: Vec<control::QuotaEntry>) {
+    for quota in quotas {
+        println!("{}: {} {}", quota.name, quota.value, quota.info);
+    }
+}
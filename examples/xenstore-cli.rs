@@ -1,5 +1,11 @@
+use std::time::Duration;
+
 use clap::{Parser, Subcommand};
-use xenstore_rs::{unix::XsUnix, Xs};
+use xenstore_rs::{
+    control::{self, LiveUpdate, LiveUpdateImage},
+    unix::XsUnix,
+    Xs, XsControl,
+};
 
 /// Demo/test tool for xenstore Rust bindings
 #[derive(Parser)]
@@ -25,6 +31,11 @@ enum Command {
         #[arg()]
         path: String,
     },
+    /// Create Xenstore directory (and its parents)
+    Mkdir {
+        #[arg()]
+        path: String,
+    },
     /// Write value to Xenstore path
     Write {
         #[arg()]
@@ -32,6 +43,77 @@ enum Command {
         #[arg()]
         data: String,
     },
+    /// Run a xenstored control command
+    Control {
+        #[command(subcommand)]
+        command: ControlCommand,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(disable_help_subcommand = true)]
+enum ControlCommand {
+    /// Enable or disable xenstored logging
+    Log {
+        #[arg(value_parser = ["on", "off"])]
+        state: String,
+    },
+    /// Set xenstored log file
+    Logfile {
+        #[arg()]
+        file: String,
+    },
+    /// Show xenstored memory usage (or write it to a file)
+    Memreport {
+        #[arg()]
+        file: Option<String>,
+    },
+    /// Show global hard quotas (or the usage of a domain)
+    Quota {
+        #[arg(long)]
+        domid: Option<u16>,
+    },
+    /// Set a global hard quota
+    QuotaSet {
+        #[arg()]
+        name: String,
+        #[arg()]
+        value: u32,
+    },
+    /// Show global soft quotas
+    QuotaSoft,
+    /// Set a global soft quota
+    QuotaSoftSet {
+        #[arg()]
+        name: String,
+        #[arg()]
+        value: u32,
+    },
+    /// Print a message in xenstored log
+    Print {
+        #[arg()]
+        message: String,
+    },
+    /// List xenstored control commands
+    Help,
+    /// Live update xenstored
+    LiveUpdate {
+        /// New xenstored binary (path as seen by xenstored unless --binary)
+        #[arg()]
+        file: String,
+        /// Upload the binary to xenstored (e.g xenstore stubdomain)
+        #[arg(long)]
+        binary: bool,
+        /// Command line of the new xenstored
+        #[arg(short, long)]
+        cmdline: Option<String>,
+        /// Seconds to wait for xenstored to be quiescent
+        #[arg(short, long)]
+        timeout: Option<u64>,
+        /// Update even with pending transactions
+        #[arg(short = 'F', long)]
+        force: bool,
+    },
 }
 
 fn main() {
@@ -43,27 +125,91 @@ fn main() {
         Command::List { path } => cmd_list(&mut xs, &path),
         Command::Read { path } => cmd_read(&mut xs, &path),
         Command::Rm { path } => cmd_rm(&mut xs, &path),
+        Command::Mkdir { path } => cmd_mkdir(&mut xs, &path),
         Command::Write { path, data } => cmd_write(&mut xs, &path, &data),
+        Command::Control { command } => cmd_control(&xs, command),
     }
 }
 
-fn cmd_list(xs: &mut impl Xs, path: &String) {
-    let values = xs.directory(&path).expect("path should be readable");
+fn cmd_list(xs: &mut impl Xs, path: &str) {
+    let values = xs.directory(path).expect("path should be readable");
     for value in values {
         println!("{}", value);
     }
 }
 
-fn cmd_read(xs: &mut impl Xs, path: &String) {
-    let value = xs.read(&path).expect("path should be readable");
+fn cmd_read(xs: &mut impl Xs, path: &str) {
+    let value = xs.read(path).expect("path should be readable");
     println!("{}", value);
 }
 
-fn cmd_rm(xs: &mut impl Xs, path: &String) {
-    xs.rm(&path).expect("cannot rm xenstore path");
+fn cmd_rm(xs: &mut impl Xs, path: &str) {
+    xs.rm(path).expect("cannot rm xenstore path");
+}
+
+fn cmd_mkdir(xs: &mut impl Xs, path: &str) {
+    xs.mkdir(path).expect("cannot create xenstore directory");
 }
 
-fn cmd_write(xs: &mut impl Xs, path: &String, data: &String) {
-    xs.write(&path, &data)
-        .expect("cannot write to xenstore path");
+fn cmd_write(xs: &mut impl Xs, path: &str, data: &str) {
+    xs.write(path, data).expect("cannot write to xenstore path");
+}
+
+fn cmd_control(xs: &impl XsControl, command: ControlCommand) {
+    match command {
+        ControlCommand::Log { state } => xs.control(&control::Log(state == "on")),
+        ControlCommand::Logfile { file } => xs.control(&control::Logfile(&file)),
+        ControlCommand::Memreport { file: Some(file) } => {
+            xs.control(&control::MemreportToFile(&file))
+        }
+        ControlCommand::Memreport { file: None } => xs
+            .control(&control::Memreport)
+            .map(|report| println!("{report}")),
+        ControlCommand::Quota { domid } => xs.control(&control::Quota { domid }).map(print_quotas),
+        ControlCommand::QuotaSet { name, value } => {
+            xs.control(&control::QuotaSet { name: &name, value })
+        }
+        ControlCommand::QuotaSoft => xs.control(&control::QuotaSoft).map(print_quotas),
+        ControlCommand::QuotaSoftSet { name, value } => {
+            xs.control(&control::QuotaSoftSet { name: &name, value })
+        }
+        ControlCommand::Print { message } => xs.control(&control::Print(&message)),
+        ControlCommand::Help => xs.control(&control::Help).map(|entries| {
+            for entry in entries {
+                let usage = entry.usage.replace('\n', &format!("\n{:16}", ""));
+                println!("{:<16}{usage}", entry.command);
+            }
+        }),
+        ControlCommand::LiveUpdate {
+            file,
+            binary,
+            cmdline,
+            timeout,
+            force,
+        } => {
+            let data;
+            let image = if binary {
+                data = std::fs::read(&file).expect("binary should be readable");
+                LiveUpdateImage::Binary(&data)
+            } else {
+                LiveUpdateImage::File(&file)
+            };
+
+            let update = LiveUpdate {
+                cmdline: cmdline.as_deref(),
+                timeout: timeout.map(Duration::from_secs),
+                force,
+                ..LiveUpdate::new(image)
+            };
+
+            xs.live_update(&update)
+        }
+    }
+    .expect("control command should succeed");
+}
+
+fn print_quotas(quotas: Vec<control::QuotaEntry>) {
+    for quota in quotas {
+        println!("{}: {} {}", quota.name, quota.value, quota.info);
+    }
 }
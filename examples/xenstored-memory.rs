@@ -0,0 +1,28 @@
+use clap::Parser;
+use tokio::net::UnixListener;
+use xenstore_rs::{memory::XsMemoryStore, server::XsServer};
+
+/// In-memory xenstored, for testing xenstore clients without Xen
+#[derive(Parser)]
+struct Cli {
+    /// Path of the socket to listen on (e.g XENSTORED_PATH of the clients)
+    #[arg()]
+    path: String,
+    /// Domain on behalf of which the connections are served
+    #[arg(long, default_value_t = 0)]
+    domid: u16,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    colog::init();
+    let cli = Cli::parse();
+
+    let listener = UnixListener::bind(&cli.path).expect("socket should be bound");
+    let server = XsServer::new(XsMemoryStore::new());
+
+    server
+        .serve(listener, cli.domid)
+        .await
+        .expect("server should keep accepting connections");
+}
@@ -0,0 +1,26 @@
+//! Parse untrusted bytes as the payload of an error message.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xenstore_rs::{protocol::XsMessage, XsError, XsMessageType};
+
+fuzz_target!(|data: &[u8]| {
+    let message = XsMessage {
+        msg_type: XsMessageType::Error,
+        request_id: 0,
+        tx_id: 0,
+        payload: data.into(),
+    };
+
+    let error = message.parse_error(XsMessageType::Read, Some("/fuzz"));
+
+    // An error code must be reported as such.
+    if let Some(errno) = message.errno() {
+        assert_eq!(
+            XsError::from_io(&error).and_then(XsError::errno),
+            Some(&errno)
+        );
+    }
+
+    let _ = message.check_response(XsMessageType::Read, None);
+});
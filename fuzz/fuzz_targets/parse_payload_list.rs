@@ -0,0 +1,26 @@
+//! Parse untrusted bytes as a payload made of NUL separated strings.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xenstore_rs::{
+    protocol::{parse_nul_list, XsMessage},
+    XsMessageType,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let message = XsMessage {
+        msg_type: XsMessageType::Directory,
+        request_id: 0,
+        tx_id: 0,
+        payload: data.into(),
+    };
+
+    if let Ok(list) = message.parse_payload_list() {
+        // Strings don't contain NUL (they are separated by NUL).
+        assert!(list.iter().all(|s| !s.contains('\0')));
+    }
+
+    let _ = parse_nul_list(data);
+    let _ = message.parse_directory_part();
+    let _ = message.parse_payload_perms();
+});
@@ -0,0 +1,9 @@
+//! Parse a message from untrusted bytes.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xenstore_rs::protocol::XsMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = XsMessage::read_from(&mut &data[..]);
+});
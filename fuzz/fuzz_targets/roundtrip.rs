@@ -0,0 +1,50 @@
+//! Check that writing a message then reading it back gives the same message
+//! (and the other way around).
+#![no_main]
+
+use std::convert::TryInto;
+
+use libfuzzer_sys::fuzz_target;
+use xenstore_rs::{
+    protocol::{XsMessage, XENSTORE_PAYLOAD_MAX},
+    XsMessageType,
+};
+
+fuzz_target!(|data: &[u8]| {
+    // Bytes -> message -> bytes
+    if let Ok(message) = XsMessage::read_from(&mut &data[..]) {
+        let mut buffer = Vec::new();
+        message
+            .write_to(&mut buffer)
+            .expect("message should be written");
+
+        assert_eq!(buffer, data[..buffer.len()]);
+    }
+
+    // Message -> bytes -> message
+    let Some((header, payload)) = data.split_first_chunk::<12>() else {
+        return;
+    };
+
+    let field =
+        |index: usize| u32::from_ne_bytes(header[4 * index..4 * (index + 1)].try_into().unwrap());
+
+    let message = XsMessage {
+        msg_type: XsMessageType::from(field(0)),
+        request_id: field(1),
+        tx_id: field(2),
+        payload: payload[..payload.len().min(XENSTORE_PAYLOAD_MAX)].into(),
+    };
+
+    let mut buffer = Vec::new();
+    message
+        .write_to(&mut buffer)
+        .expect("message should be written");
+
+    let read_message = XsMessage::read_from(&mut &buffer[..]).expect("message should be read");
+
+    assert_eq!(read_message.msg_type, message.msg_type);
+    assert_eq!(read_message.request_id, message.request_id);
+    assert_eq!(read_message.tx_id, message.tx_id);
+    assert_eq!(read_message.payload, message.payload);
+});
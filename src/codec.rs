@@ -0,0 +1,205 @@
+//! Tokio codec for xenstore messages.
+//!
+//! Allows to read and write [XsMessage] over any async stream using
+//! [tokio_util::codec::Framed] (or `FramedRead`/`FramedWrite`).
+
+use std::{convert::TryInto, io};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    protocol::{check_payload_len, XsMessage, XsMessageHeader},
+    XsProtocolError,
+};
+
+const HEADER_SIZE: usize = XsMessageHeader::SIZE;
+
+/// [XsMessage] codec.
+///
+/// Payloads larger than [crate::protocol::XENSTORE_PAYLOAD_MAX] are rejected both ways
+/// (with [XsProtocolError::PayloadTooLarge]).
+/// Messages of unknown type are decoded (see [crate::XsMessageType::Unknown]),
+/// leaving it to the caller to handle them.
+/// Once an invalid message has been received, the stream can't be trusted to be in
+/// sync anymore and the decoder is poisoned (fails with [XsProtocolError::Poisoned]).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XsMessageCodec {
+    poisoned: bool,
+}
+
+impl XsMessageCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if the decoder has been poisoned by an invalid message.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+impl Decoder for XsMessageCodec {
+    type Item = XsMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<XsMessage>> {
+        if self.poisoned {
+            return Err(XsProtocolError::Poisoned.into());
+        }
+
+        if src.len() < HEADER_SIZE {
+            src.reserve(HEADER_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let header = XsMessageHeader::from_bytes(src[..HEADER_SIZE].try_into().unwrap());
+        let len = header.len as usize;
+
+        if let Err(e) = check_payload_len(len) {
+            // Don't try to skip the payload, header itself may be garbage.
+            self.poisoned = true;
+            src.clear();
+            return Err(e);
+        }
+
+        if src.len() < HEADER_SIZE + len {
+            src.reserve(HEADER_SIZE + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_SIZE);
+        let payload = src.split_to(len);
+
+        Ok(Some(XsMessage::from_parts(
+            header,
+            payload.to_vec().into_boxed_slice(),
+        )))
+    }
+}
+
+impl Encoder<XsMessage> for XsMessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: XsMessage, dst: &mut BytesMut) -> io::Result<()> {
+        check_payload_len(message.payload.len())?;
+        dst.reserve(HEADER_SIZE + message.payload.len());
+
+        dst.put_slice(&message.header().to_bytes());
+        dst.put_slice(&message.payload);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocol::XENSTORE_PAYLOAD_MAX, XsError, XsMessageType};
+
+    fn encoded(message: &XsMessage) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        XsMessageCodec::new()
+            .encode(message.clone(), &mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    fn message() -> XsMessage {
+        XsMessage {
+            msg_type: XsMessageType::Read,
+            request_id: 1,
+            tx_id: 2,
+            payload: b"/a\0".as_slice().into(),
+        }
+    }
+
+    #[test]
+    fn decode_whole() {
+        let mut codec = XsMessageCodec::new();
+        let mut src = encoded(&message());
+        src.extend_from_slice(&encoded(&message()));
+
+        for _ in 0..2 {
+            let decoded = codec.decode(&mut src).unwrap().unwrap();
+            assert_eq!(decoded.header(), message().header());
+            assert_eq!(decoded.payload, message().payload);
+        }
+
+        assert!(src.is_empty());
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_partial_header() {
+        let mut codec = XsMessageCodec::new();
+        let bytes = encoded(&message());
+        let mut src = BytesMut::from(&bytes[..HEADER_SIZE - 1]);
+
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        // Nothing is consumed until the whole message is there.
+        assert_eq!(src.len(), HEADER_SIZE - 1);
+
+        src.extend_from_slice(&bytes[HEADER_SIZE - 1..]);
+        let decoded = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.payload, message().payload);
+    }
+
+    #[test]
+    fn decode_partial_payload() {
+        let mut codec = XsMessageCodec::new();
+        let bytes = encoded(&message());
+        let mut src = BytesMut::from(&bytes[..HEADER_SIZE + 1]);
+
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), HEADER_SIZE + 1);
+
+        src.extend_from_slice(&bytes[HEADER_SIZE + 1..]);
+        let decoded = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.payload, message().payload);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_oversized_poisons() {
+        let mut codec = XsMessageCodec::new();
+        let header = XsMessageHeader {
+            len: XENSTORE_PAYLOAD_MAX as u32 + 1,
+            ..message().header()
+        };
+        let mut src = BytesMut::from(header.to_bytes().as_slice());
+
+        let error = codec.decode(&mut src).unwrap_err();
+        assert!(matches!(
+            XsError::from_io(&error),
+            Some(XsError::Protocol(XsProtocolError::PayloadTooLarge(_)))
+        ));
+        assert!(codec.is_poisoned());
+
+        // Even valid messages are rejected afterwards.
+        let mut src = encoded(&message());
+        for _ in 0..2 {
+            let error = codec.decode(&mut src).unwrap_err();
+            assert!(matches!(
+                XsError::from_io(&error),
+                Some(XsError::Protocol(XsProtocolError::Poisoned))
+            ));
+        }
+    }
+
+    #[test]
+    fn encode_oversized() {
+        let message = XsMessage {
+            payload: vec![0; XENSTORE_PAYLOAD_MAX + 1].into(),
+            ..message()
+        };
+        let mut dst = BytesMut::new();
+
+        let error = XsMessageCodec::new().encode(message, &mut dst).unwrap_err();
+        assert!(matches!(
+            XsError::from_io(&error),
+            Some(XsError::Protocol(XsProtocolError::PayloadTooLarge(_)))
+        ));
+        assert!(dst.is_empty());
+    }
+}
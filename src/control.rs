@@ -0,0 +1,604 @@
+//! Xenstored control commands (XS_CONTROL).
+//!
+//! Commands are run with [crate::XsControl::control] (or its async variant),
+//! each giving its own structured output.
+//!
+//! ```no_run
+//! use xenstore_rs::{control, unix::XsUnix, XsControl};
+//!
+//! let xs = XsUnix::new()?;
+//! xs.control(&control::Log(true))?;
+//!
+//! for quota in xs.control(&control::Quota { domid: None })? {
+//!     println!("{}: {}", quota.name, quota.value);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    io::{self, ErrorKind},
+    str,
+    time::Duration,
+};
+
+use crate::protocol::{XsRequest, XENSTORE_PAYLOAD_MAX};
+
+/// Delay between two attempts to start a live update while xenstored is busy.
+pub const LIVE_UPDATE_POLL: Duration = Duration::from_secs(1);
+
+/// Payload prefix of a live update data chunk (see [LiveUpdateCommand::Data]).
+pub(crate) const LIVE_UPDATE_DATA: &[u8] = b"live-update\0-d\0";
+
+/// Xenstored control command.
+pub trait XsControlCommand {
+    type Output;
+
+    /// Arguments of the command (starting with the command name).
+    fn args(&self) -> Vec<Box<str>>;
+
+    /// Raw data following the arguments (e.g live update chunk).
+    fn data(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Parse the raw output of the command.
+    fn parse(&self, output: &[u8]) -> io::Result<Self::Output>;
+
+    /// Build the request of the command.
+    fn request(&self) -> XsRequest {
+        XsRequest::Control {
+            args: self.args(),
+            data: self.data().map(Box::from),
+        }
+    }
+}
+
+fn output_str(output: &[u8]) -> io::Result<&str> {
+    // Discard latest NUL character (if present)
+    str::from_utf8(output.strip_suffix(&[0]).unwrap_or(output))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Parse the output of a command that is only acknowledged, any other output
+/// being an error message.
+fn parse_ok(output: &[u8]) -> io::Result<()> {
+    match output_str(output)? {
+        "OK" => Ok(()),
+        message => Err(io::Error::other(message.to_string())),
+    }
+}
+
+/// Quota setting, as reported by [Quota] and [QuotaSoft].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaEntry {
+    pub name: Box<str>,
+    pub value: u32,
+    /// Rest of the line (e.g description or maximum).
+    pub info: Box<str>,
+}
+
+/// Parse `<name>: <value> <info>` lines, ignoring the other ones (e.g headers).
+fn parse_quotas(output: &[u8]) -> io::Result<Vec<QuotaEntry>> {
+    Ok(output_str(output)?
+        .lines()
+        .filter_map(|line| {
+            let (name, rest) = line.split_once(':')?;
+            let rest = rest.trim_start();
+            let (value, info) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+            Some(QuotaEntry {
+                name: name.trim().into(),
+                value: value.parse().ok()?,
+                info: info.trim().into(),
+            })
+        })
+        .collect())
+}
+
+/// Control command, as reported by [Help].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HelpEntry {
+    pub command: Box<str>,
+    /// Arguments of the command, possibly with a description.
+    pub usage: Box<str>,
+}
+
+/// Raw command, giving its raw output (e.g for commands without wrapper).
+#[derive(Clone, Copy, Debug)]
+pub struct Raw<'a>(pub &'a [&'a str]);
+
+impl XsControlCommand for Raw<'_> {
+    type Output = Box<[u8]>;
+
+    fn args(&self) -> Vec<Box<str>> {
+        self.0.iter().map(|&arg| arg.into()).collect()
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<Box<[u8]>> {
+        Ok(output.into())
+    }
+}
+
+/// Enable or disable xenstored logging (`log on|off`).
+#[derive(Clone, Copy, Debug)]
+pub struct Log(pub bool);
+
+impl XsControlCommand for Log {
+    type Output = ();
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec!["log".into(), if self.0 { "on" } else { "off" }.into()]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<()> {
+        parse_ok(output)
+    }
+}
+
+/// Set the log file of xenstored (`logfile <file>`).
+#[derive(Clone, Copy, Debug)]
+pub struct Logfile<'a>(pub &'a str);
+
+impl XsControlCommand for Logfile<'_> {
+    type Output = ();
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec!["logfile".into(), self.0.into()]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<()> {
+        parse_ok(output)
+    }
+}
+
+/// Get a report of the memory used by xenstored (`memreport`).
+#[derive(Clone, Copy, Debug)]
+pub struct Memreport;
+
+impl XsControlCommand for Memreport {
+    type Output = Box<str>;
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec!["memreport".into()]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<Box<str>> {
+        output_str(output).map(Box::from)
+    }
+}
+
+/// Write a report of the memory used by xenstored to a file (`memreport <file>`).
+#[derive(Clone, Copy, Debug)]
+pub struct MemreportToFile<'a>(pub &'a str);
+
+impl XsControlCommand for MemreportToFile<'_> {
+    type Output = ();
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec!["memreport".into(), self.0.into()]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<()> {
+        parse_ok(output)
+    }
+}
+
+/// Get the hard quotas, either the global ones or the usage of domain `domid`
+/// (`quota [<domid>]`).
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub domid: Option<u16>,
+}
+
+impl XsControlCommand for Quota {
+    type Output = Vec<QuotaEntry>;
+
+    fn args(&self) -> Vec<Box<str>> {
+        let mut args = vec!["quota".into()];
+        args.extend(self.domid.map(|domid| domid.to_string().into()));
+        args
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<Vec<QuotaEntry>> {
+        parse_quotas(output)
+    }
+}
+
+/// Set a global hard quota (`quota set <name> <value>`).
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaSet<'a> {
+    pub name: &'a str,
+    pub value: u32,
+}
+
+impl XsControlCommand for QuotaSet<'_> {
+    type Output = ();
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec![
+            "quota".into(),
+            "set".into(),
+            self.name.into(),
+            self.value.to_string().into(),
+        ]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<()> {
+        parse_ok(output)
+    }
+}
+
+/// Get the global soft quotas, only logged when exceeded (`quota-soft`).
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaSoft;
+
+impl XsControlCommand for QuotaSoft {
+    type Output = Vec<QuotaEntry>;
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec!["quota-soft".into()]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<Vec<QuotaEntry>> {
+        parse_quotas(output)
+    }
+}
+
+/// Set a global soft quota (`quota-soft set <name> <value>`).
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaSoftSet<'a> {
+    pub name: &'a str,
+    pub value: u32,
+}
+
+impl XsControlCommand for QuotaSoftSet<'_> {
+    type Output = ();
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec![
+            "quota-soft".into(),
+            "set".into(),
+            self.name.into(),
+            self.value.to_string().into(),
+        ]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<()> {
+        parse_ok(output)
+    }
+}
+
+/// Print a message in the xenstored log (`print <string>`).
+#[derive(Clone, Copy, Debug)]
+pub struct Print<'a>(pub &'a str);
+
+impl XsControlCommand for Print<'_> {
+    type Output = ();
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec!["print".into(), self.0.into()]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<()> {
+        parse_ok(output)
+    }
+}
+
+/// List the control commands supported by xenstored (`help`).
+#[derive(Clone, Copy, Debug)]
+pub struct Help;
+
+impl XsControlCommand for Help {
+    type Output = Vec<HelpEntry>;
+
+    fn args(&self) -> Vec<Box<str>> {
+        vec!["help".into()]
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<Vec<HelpEntry>> {
+        let mut entries: Vec<(&str, String)> = Vec::new();
+
+        for line in output_str(output)?.lines() {
+            match entries.last_mut() {
+                // Indented lines continue the usage of the previous command.
+                Some((_, usage)) if line.starts_with(char::is_whitespace) => {
+                    usage.push('\n');
+                    usage.push_str(line.trim());
+                }
+                // Skip blank lines and headers (e.g `Valid commands:`).
+                _ if line.trim().is_empty() || line.ends_with(':') => {}
+                _ => {
+                    let (command, usage) =
+                        line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                    entries.push((command, usage.trim().to_string()));
+                }
+            }
+        }
+
+        Ok(entries
+            .into_iter()
+            .map(|(command, usage)| HelpEntry {
+                command: command.into(),
+                usage: usage.into(),
+            })
+            .collect())
+    }
+}
+
+/// Status of a live update step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiveUpdateStatus {
+    Ok,
+    /// Xenstored can't start the update yet (e.g pending transactions),
+    /// [LiveUpdateCommand::Start] needs to be retried.
+    Busy,
+}
+
+/// Single step of a live update (`live-update -f|-b|-d|-c|-s|-a`),
+/// see [LiveUpdate] for the whole update.
+#[derive(Clone, Copy, Debug)]
+pub enum LiveUpdateCommand<'a> {
+    /// Use the new binary at `path` (as seen by xenstored).
+    File(&'a str),
+    /// Begin the upload of a new binary of `size` bytes.
+    Begin(usize),
+    /// Upload a chunk of the new binary.
+    Data(&'a [u8]),
+    /// Set the command line of the new xenstored.
+    Cmdline(&'a str),
+    /// Start the update, waiting up to `timeout` for xenstored to be quiescent
+    /// (or despite pending transactions if `force` is set).
+    Start {
+        timeout: Option<Duration>,
+        force: bool,
+    },
+    /// Abort the update.
+    Abort,
+}
+
+impl XsControlCommand for LiveUpdateCommand<'_> {
+    type Output = LiveUpdateStatus;
+
+    fn args(&self) -> Vec<Box<str>> {
+        let mut args: Vec<Box<str>> = vec!["live-update".into()];
+
+        match self {
+            LiveUpdateCommand::File(path) => args.extend(["-f".into(), (*path).into()]),
+            LiveUpdateCommand::Begin(size) => args.extend(["-b".into(), size.to_string().into()]),
+            LiveUpdateCommand::Data(_) => args.push("-d".into()),
+            LiveUpdateCommand::Cmdline(cmdline) => args.extend(["-c".into(), (*cmdline).into()]),
+            LiveUpdateCommand::Start { timeout, force } => {
+                args.push("-s".into());
+
+                if let Some(timeout) = timeout {
+                    args.extend(["-t".into(), timeout.as_secs().to_string().into()]);
+                }
+
+                if *force {
+                    args.push("-F".into());
+                }
+            }
+            LiveUpdateCommand::Abort => args.push("-a".into()),
+        }
+
+        args
+    }
+
+    fn data(&self) -> Option<&[u8]> {
+        match self {
+            LiveUpdateCommand::Data(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn parse(&self, output: &[u8]) -> io::Result<LiveUpdateStatus> {
+        match output_str(output)? {
+            "BUSY" => Ok(LiveUpdateStatus::Busy),
+            _ => parse_ok(output).map(|_| LiveUpdateStatus::Ok),
+        }
+    }
+}
+
+/// New xenstored of a [LiveUpdate].
+#[derive(Clone, Copy, Debug)]
+pub enum LiveUpdateImage<'a> {
+    /// Path of the new binary, as seen by xenstored (e.g daemon in dom0).
+    File(&'a str),
+    /// New binary to upload (e.g xenstore stubdomain kernel).
+    Binary(&'a [u8]),
+}
+
+/// Live update of xenstored, replacing it without losing its state
+/// (see [crate::XsControl::live_update]).
+#[derive(Clone, Copy, Debug)]
+pub struct LiveUpdate<'a> {
+    pub image: LiveUpdateImage<'a>,
+    /// Command line of the new xenstored (the current one by default).
+    pub cmdline: Option<&'a str>,
+    /// Time to wait for xenstored to be quiescent (xenstored default if [None]).
+    pub timeout: Option<Duration>,
+    /// Update even if some transactions are still pending.
+    pub force: bool,
+}
+
+impl<'a> LiveUpdate<'a> {
+    pub fn new(image: LiveUpdateImage<'a>) -> Self {
+        Self {
+            image,
+            cmdline: None,
+            timeout: None,
+            force: false,
+        }
+    }
+
+    /// Steps preparing the update (e.g upload), to run before [LiveUpdate::start].
+    pub fn prepare(&self) -> Vec<LiveUpdateCommand<'a>> {
+        let mut commands = match self.image {
+            LiveUpdateImage::File(path) => vec![LiveUpdateCommand::File(path)],
+            LiveUpdateImage::Binary(binary) => {
+                let chunk_size = XENSTORE_PAYLOAD_MAX - LIVE_UPDATE_DATA.len();
+
+                std::iter::once(LiveUpdateCommand::Begin(binary.len()))
+                    .chain(binary.chunks(chunk_size).map(LiveUpdateCommand::Data))
+                    .collect()
+            }
+        };
+
+        commands.extend(self.cmdline.map(LiveUpdateCommand::Cmdline));
+        commands
+    }
+
+    /// Step starting the update, to retry while [LiveUpdateStatus::Busy].
+    pub fn start(&self) -> LiveUpdateCommand<'a> {
+        LiveUpdateCommand::Start {
+            timeout: self.timeout,
+            force: self.force,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(name: &str, value: u32, info: &str) -> QuotaEntry {
+        QuotaEntry {
+            name: name.into(),
+            value,
+            info: info.into(),
+        }
+    }
+
+    fn help(command: &str, usage: &str) -> HelpEntry {
+        HelpEntry {
+            command: command.into(),
+            usage: usage.into(),
+        }
+    }
+
+    #[test]
+    fn quotas() {
+        let output = b"Quota settings:\n\
+            nodes            :     1000 Nodes per domain\n\
+            watches          :      128 Watches per domain\n\
+            transactions     :       10 Transactions per domain\n\0";
+        assert_eq!(
+            parse_quotas(output).unwrap(),
+            [
+                quota("nodes", 1000, "Nodes per domain"),
+                quota("watches", 128, "Watches per domain"),
+                quota("transactions", 10, "Transactions per domain"),
+            ]
+        );
+
+        // Usage of a domain.
+        let output = b"Domain 1:\n\
+            nodes            :       12 (max     1000)\n\
+            watches          :        0 (max      128)\n\0";
+        assert_eq!(
+            Quota { domid: Some(1) }.parse(output).unwrap(),
+            [
+                quota("nodes", 12, "(max     1000)"),
+                quota("watches", 0, "(max      128)"),
+            ]
+        );
+
+        assert_eq!(parse_quotas(b"\0").unwrap(), []);
+        assert!(parse_quotas(b"nodes: 1000 \xff\0").is_err());
+    }
+
+    #[test]
+    fn help_entries() {
+        let output = b"Valid commands:\n\
+            check\t\n\
+            log\t[on|off|+<switch>|-<switch>]\n\
+            logfile\t<file>\n\
+            memreport\t[<file>]\n\
+            print\t<string>\n\
+            quota\t[set <name> <val>|<domid>]\n\
+            quota-soft\t[set <name> <val>]\n\
+            live-update\t[-c <cmdline>] [-F] [-t <timeout>] <file>\n    \
+            Default timeout is 60 seconds.\n\
+            help\t\n\0";
+        assert_eq!(
+            Help.parse(output).unwrap(),
+            [
+                help("check", ""),
+                help("log", "[on|off|+<switch>|-<switch>]"),
+                help("logfile", "<file>"),
+                help("memreport", "[<file>]"),
+                help("print", "<string>"),
+                help("quota", "[set <name> <val>|<domid>]"),
+                help("quota-soft", "[set <name> <val>]"),
+                help(
+                    "live-update",
+                    "[-c <cmdline>] [-F] [-t <timeout>] <file>\nDefault timeout is 60 seconds."
+                ),
+                help("help", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn live_update_prepare() {
+        let update = LiveUpdate {
+            cmdline: Some("--verbose"),
+            ..LiveUpdate::new(LiveUpdateImage::File("/usr/sbin/xenstored"))
+        };
+        let args: Vec<_> = update
+            .prepare()
+            .iter()
+            .map(|command| command.args())
+            .collect();
+        assert_eq!(
+            args,
+            [
+                vec![
+                    "live-update".into(),
+                    "-f".into(),
+                    "/usr/sbin/xenstored".into()
+                ],
+                vec!["live-update".into(), "-c".into(), "--verbose".into()],
+            ]
+        );
+
+        // Chunks fill the whole payload.
+        let chunk_size = XENSTORE_PAYLOAD_MAX - LIVE_UPDATE_DATA.len();
+        let binary = vec![0x42; 2 * chunk_size + 1];
+        let commands = LiveUpdate::new(LiveUpdateImage::Binary(&binary)).prepare();
+
+        assert!(matches!(commands[0], LiveUpdateCommand::Begin(size) if size == binary.len()));
+        let chunks: Vec<_> = commands[1..]
+            .iter()
+            .map(|command| command.data().unwrap().len())
+            .collect();
+        assert_eq!(chunks, [chunk_size, chunk_size, 1]);
+
+        let payload = commands[1].request().encode(0, 0).payload;
+        assert_eq!(payload.len(), XENSTORE_PAYLOAD_MAX);
+        assert!(payload.starts_with(LIVE_UPDATE_DATA));
+    }
+
+    #[test]
+    fn live_update_status() {
+        let start = LiveUpdateCommand::Start {
+            timeout: Some(Duration::from_secs(60)),
+            force: true,
+        };
+        assert_eq!(
+            start.args(),
+            ["live-update", "-s", "-t", "60", "-F"].map(Box::from)
+        );
+
+        assert_eq!(start.parse(b"OK\0").unwrap(), LiveUpdateStatus::Ok);
+        assert_eq!(start.parse(b"BUSY\0").unwrap(), LiveUpdateStatus::Busy);
+
+        let error = start
+            .parse(b"Timeout reached, not all transactions closed\0")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Timeout reached, not all transactions closed"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Xenstore errors.
+//!
+//! All operations return [io::Error], errors reported by xenstore are wrapped
+//! in a [XsError] that can be retrieved with [XsError::from_io].
+
+use std::{convert::Infallible, error::Error, fmt, io, str::FromStr};
+
+use crate::protocol::{XsMessageType, XENSTORE_PAYLOAD_MAX};
+
+/// Error code reported by xenstore.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum XsErrno {
+    EINVAL,
+    EACCES,
+    EEXIST,
+    EISDIR,
+    ENOENT,
+    ENOMEM,
+    ENOSPC,
+    EIO,
+    ENOTEMPTY,
+    ENOSYS,
+    EROFS,
+    EBUSY,
+    EAGAIN,
+    EISCONN,
+    E2BIG,
+    EPERM,
+    /// Error code unknown to this implementation.
+    Other(Box<str>),
+}
+
+impl XsErrno {
+    pub fn as_str(&self) -> &str {
+        match self {
+            XsErrno::EINVAL => "EINVAL",
+            XsErrno::EACCES => "EACCES",
+            XsErrno::EEXIST => "EEXIST",
+            XsErrno::EISDIR => "EISDIR",
+            XsErrno::ENOENT => "ENOENT",
+            XsErrno::ENOMEM => "ENOMEM",
+            XsErrno::ENOSPC => "ENOSPC",
+            XsErrno::EIO => "EIO",
+            XsErrno::ENOTEMPTY => "ENOTEMPTY",
+            XsErrno::ENOSYS => "ENOSYS",
+            XsErrno::EROFS => "EROFS",
+            XsErrno::EBUSY => "EBUSY",
+            XsErrno::EAGAIN => "EAGAIN",
+            XsErrno::EISCONN => "EISCONN",
+            XsErrno::E2BIG => "E2BIG",
+            XsErrno::EPERM => "EPERM",
+            XsErrno::Other(code) => code,
+        }
+    }
+
+    /// Closest [io::ErrorKind] of this error code.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            XsErrno::EINVAL => io::ErrorKind::InvalidInput,
+            XsErrno::EACCES => io::ErrorKind::PermissionDenied,
+            XsErrno::EEXIST => io::ErrorKind::AlreadyExists,
+            XsErrno::EISDIR => io::ErrorKind::IsADirectory,
+            XsErrno::ENOENT => io::ErrorKind::NotFound,
+            XsErrno::ENOMEM => io::ErrorKind::OutOfMemory,
+            XsErrno::ENOSPC => io::ErrorKind::OutOfMemory,
+            XsErrno::EIO => io::ErrorKind::Other,
+            XsErrno::ENOTEMPTY => io::ErrorKind::DirectoryNotEmpty,
+            XsErrno::ENOSYS => io::ErrorKind::Unsupported,
+            XsErrno::EROFS => io::ErrorKind::PermissionDenied,
+            XsErrno::EBUSY => io::ErrorKind::ResourceBusy,
+            XsErrno::EAGAIN => io::ErrorKind::WouldBlock,
+            XsErrno::EISCONN => io::ErrorKind::AddrInUse,
+            XsErrno::E2BIG => io::ErrorKind::InvalidData,
+            XsErrno::EPERM => io::ErrorKind::PermissionDenied,
+            XsErrno::Other(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl FromStr for XsErrno {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Infallible> {
+        Ok(match s {
+            "EINVAL" => XsErrno::EINVAL,
+            "EACCES" => XsErrno::EACCES,
+            "EEXIST" => XsErrno::EEXIST,
+            "EISDIR" => XsErrno::EISDIR,
+            "ENOENT" => XsErrno::ENOENT,
+            "ENOMEM" => XsErrno::ENOMEM,
+            "ENOSPC" => XsErrno::ENOSPC,
+            "EIO" => XsErrno::EIO,
+            "ENOTEMPTY" => XsErrno::ENOTEMPTY,
+            "ENOSYS" => XsErrno::ENOSYS,
+            "EROFS" => XsErrno::EROFS,
+            "EBUSY" => XsErrno::EBUSY,
+            "EAGAIN" => XsErrno::EAGAIN,
+            "EISCONN" => XsErrno::EISCONN,
+            "E2BIG" => XsErrno::E2BIG,
+            "EPERM" => XsErrno::EPERM,
+            code => XsErrno::Other(code.into()),
+        })
+    }
+}
+
+impl fmt::Display for XsErrno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Violation of the xenstore wire protocol.
+///
+/// The connection can't be trusted to be in sync afterwards, so it is torn down
+/// and further operations fail with [XsProtocolError::Poisoned].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum XsProtocolError {
+    /// Message payload is larger than [XENSTORE_PAYLOAD_MAX].
+    PayloadTooLarge(usize),
+    /// Connection is unusable due to a previous protocol error.
+    Poisoned,
+}
+
+impl fmt::Display for XsProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XsProtocolError::PayloadTooLarge(len) => {
+                write!(f, "Payload is too large ({len} > {XENSTORE_PAYLOAD_MAX})")
+            }
+            XsProtocolError::Poisoned => f.write_str("Connection poisoned by a protocol error"),
+        }
+    }
+}
+
+/// Xenstore error.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum XsError {
+    /// Xenstore failed a request.
+    Xenstore {
+        errno: XsErrno,
+        /// Type of the failed request.
+        request: XsMessageType,
+        /// Node path of the failed request (if any).
+        path: Option<Box<str>>,
+    },
+    /// Xenstore wire protocol violation.
+    Protocol(XsProtocolError),
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl XsError {
+    /// Get the [XsError] wrapped in a [io::Error] (if any).
+    pub fn from_io(error: &io::Error) -> Option<&XsError> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// Get the error code reported by xenstore (if any).
+    pub fn errno(&self) -> Option<&XsErrno> {
+        match self {
+            XsError::Xenstore { errno, .. } => Some(errno),
+            XsError::Protocol(_) | XsError::Io(_) => None,
+        }
+    }
+
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            XsError::Xenstore { errno, .. } => errno.kind(),
+            XsError::Protocol(_) => io::ErrorKind::InvalidData,
+            XsError::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for XsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XsError::Xenstore {
+                errno,
+                request,
+                path: Some(path),
+            } => write!(f, "XS interface error {errno} ({request:?} {path})"),
+            XsError::Xenstore {
+                errno,
+                request,
+                path: None,
+            } => write!(f, "XS interface error {errno} ({request:?})"),
+            XsError::Protocol(e) => write!(f, "XS protocol error: {e}"),
+            XsError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for XsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            XsError::Xenstore { .. } | XsError::Protocol(_) => None,
+            XsError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<XsError> for io::Error {
+    fn from(error: XsError) -> Self {
+        match error {
+            XsError::Io(e) => e,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
+
+impl From<XsProtocolError> for io::Error {
+    fn from(error: XsProtocolError) -> Self {
+        XsError::Protocol(error).into()
+    }
+}
+
+impl From<io::Error> for XsError {
+    fn from(error: io::Error) -> Self {
+        if XsError::from_io(&error).is_none() {
+            return XsError::Io(error);
+        }
+
+        // Checked above, can't fail.
+        *error
+            .into_inner()
+            .and_then(|e| e.downcast().ok())
+            .expect("io::Error should wrap a XsError")
+    }
+}
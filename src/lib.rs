@@ -5,7 +5,13 @@
 //!
 //! Check docs/misc/xenstore.txt in xen source code for detailed informations.
 
-pub(crate) mod wire;
+pub mod control;
+mod error;
+mod permission;
+pub mod protocol;
+
+#[cfg(feature = "codec")]
+pub mod codec;
 
 #[cfg(not(target_os = "windows"))]
 #[cfg(feature = "unix")]
@@ -15,7 +21,26 @@ pub mod unix;
 #[cfg(feature = "async-tokio")]
 pub mod tokio;
 
-use std::io;
+#[cfg(feature = "memory")]
+pub mod memory;
+
+#[cfg(not(target_os = "windows"))]
+#[cfg(feature = "server")]
+pub mod server;
+
+#[cfg(feature = "async")]
+use futures::future::BoxFuture;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::{io, thread, time::Duration};
+
+use control::{
+    LiveUpdate, LiveUpdateCommand, LiveUpdateStatus, XsControlCommand, LIVE_UPDATE_POLL,
+};
+
+pub use error::{XsErrno, XsError, XsProtocolError};
+pub use permission::{InvalidPermission, XsAccess, XsPermission};
+pub use protocol::XsMessageType;
 
 /// Xenstore base trait.
 /// All xenstore implementations must implement this trait.
@@ -24,13 +49,55 @@ pub trait Xs {
     fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>>;
 
     /// Read a node.
-    fn read(&self, path: &str) -> io::Result<Box<str>>;
+    fn read(&self, path: &str) -> io::Result<Box<str>> {
+        String::from_utf8(self.read_bytes(path)?.into_vec())
+            .map(String::into_boxed_str)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Read a node as raw bytes.
+    fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>>;
 
     /// Write a node.
-    fn write(&self, path: &str, data: &str) -> io::Result<()>;
+    fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.write_bytes(path, data.as_bytes())
+    }
+
+    /// Write raw bytes to a node.
+    fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()>;
 
     /// Remove a node.
     fn rm(&self, path: &str) -> io::Result<()>;
+
+    /// Create a directory node (with an empty value).
+    ///
+    /// Alike `mkdir -p`, missing parents are created and it is not an error
+    /// if the node already exists (its value is left unchanged).
+    fn mkdir(&self, path: &str) -> io::Result<()>;
+
+    /// Get the permissions of a node.
+    fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>>;
+
+    /// Set the permissions of a node.
+    ///
+    /// The first permission defines the owner of the node and the access of
+    /// the domains that are not listed afterwards.
+    fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()>;
+
+    /// Get the home path of a domain (e.g `/local/domain/<domid>`).
+    fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>>;
+
+    /// Get the id of the local domain, read from its relative `domid` node.
+    fn get_domid(&self) -> io::Result<u16> {
+        self.read("domid")?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Get the home path of the local domain.
+    fn get_home_path(&self) -> io::Result<Box<str>> {
+        self.get_domain_path(self.get_domid()?)
+    }
 }
 
 /// Xenstore transaction capability trait.
@@ -39,16 +106,48 @@ pub trait Xs {
 /// This span can be used to make operation within this transaction and be effective
 /// only if the transaction is commited ([XsTransactionSpan::commit]).
 ///
-/// If you want to discard the transaction and make its changes effective, use
+/// If you want to make the changes of the transaction effective, use
 /// [XsTransactionSpan::commit].
 ///
 /// # Drop
 ///
-/// [Drop] is called on a transaction, it is aborted.
+/// If [Drop] is called on a transaction that is not commited, it is aborted.
 pub trait XsTransaction: Xs {
-    type Span: Xs; // + 'static ?
+    type Span: XsTransactionSpan; // + 'static ?
 
     fn transaction(&self) -> io::Result<Self::Span>;
+
+    /// Run `f` inside a transaction and commit it, replaying the whole transaction
+    /// if it conflicts with another one (EAGAIN) using [TransactionRetry::default].
+    ///
+    /// If `f` fails, the transaction is aborted and the error is returned as is.
+    fn transact<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnMut(&Self::Span) -> io::Result<T>,
+    {
+        self.transact_with(TransactionRetry::default(), f)
+    }
+
+    /// [XsTransaction::transact] with a custom retry policy.
+    fn transact_with<T, F>(&self, retry: TransactionRetry, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&Self::Span) -> io::Result<T>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let span = self.transaction()?;
+            let value = f(&span)?;
+
+            match span.commit() {
+                Err(e) if retry.should_retry(&e, attempt) => {
+                    thread::sleep(retry.backoff(attempt));
+                    attempt += 1;
+                }
+                result => return result.map(|_| value),
+            }
+        }
+    }
 }
 
 /// Refer to [XsTransaction] for more information.
@@ -57,6 +156,138 @@ pub trait XsTransactionSpan: Xs {
     fn commit(self) -> io::Result<()>;
 }
 
+/// Xenstore blocking watch capability trait.
+pub trait XsWatch {
+    type Watch: Iterator<Item = io::Result<Box<str>>>;
+
+    /// Create a [Iterator] yielding paths of updated nodes/subnodes.
+    fn watch(&self, path: &str) -> io::Result<Self::Watch>;
+}
+
+/// Xenstore domain management capability trait.
+///
+/// These operations are restricted to privileged domains (e.g toolstack in dom0).
+pub trait XsDomainControl {
+    /// Introduce a domain to xenstore, providing the frame number of its
+    /// xenstore ring page (mfn, or gfn for HVM guests) and its event channel.
+    fn introduce(&self, domid: u16, mfn: u64, evtchn: u32) -> io::Result<()>;
+
+    /// Release a domain (e.g when it is destroyed).
+    fn release(&self, domid: u16) -> io::Result<()>;
+
+    /// Check if a domain has been introduced.
+    fn is_domain_introduced(&self, domid: u16) -> io::Result<bool>;
+
+    /// Resume a domain after it has been suspended.
+    fn resume(&self, domid: u16) -> io::Result<()>;
+
+    /// Make `target` the target of `domid` (e.g device model stubdomain),
+    /// giving `domid` the same privileges as `target`.
+    fn set_target(&self, domid: u16, target: u16) -> io::Result<()>;
+
+    /// Get the features supported by xenstored, or the ones enabled for domain `domid`
+    /// (see `XENSTORE_SERVER_FEATURE_*` in [crate::protocol]).
+    fn get_features(&self, domid: Option<u16>) -> io::Result<u32>;
+
+    /// Set the features enabled for domain `domid`.
+    fn set_features(&self, domid: u16, features: u32) -> io::Result<()>;
+
+    /// List the names of the quotas supported by xenstored.
+    fn quota_names(&self) -> io::Result<Vec<Box<str>>>;
+
+    /// Get the value of a quota, either the global one or the one of domain `domid`.
+    fn get_quota(&self, domid: Option<u16>, quota: &str) -> io::Result<u32>;
+
+    /// Set the value of a quota, either the global one or the one of domain `domid`.
+    fn set_quota(&self, domid: Option<u16>, quota: &str, value: u32) -> io::Result<()>;
+}
+
+/// Xenstored control capability trait (see [control]).
+///
+/// These operations are restricted to privileged domains and their support
+/// depends on the xenstored implementation.
+pub trait XsControl {
+    /// Run a control command.
+    fn control<C: XsControlCommand>(&self, command: &C) -> io::Result<C::Output>;
+
+    /// Live update xenstored, uploading the new binary if needed and starting the
+    /// update once xenstored is quiescent (or the timeout of `update` is reached).
+    ///
+    /// The update is aborted on failure.
+    fn live_update(&self, update: &LiveUpdate) -> io::Result<()> {
+        let result = (|| {
+            for command in update.prepare() {
+                self.control(&command)?;
+            }
+
+            while self.control(&update.start())? == LiveUpdateStatus::Busy {
+                thread::sleep(LIVE_UPDATE_POLL);
+            }
+
+            Ok(())
+        })();
+
+        if result.is_err() {
+            // Don't leave a partial update behind, the original error is more relevant.
+            self.control(&LiveUpdateCommand::Abort).ok();
+        }
+
+        result
+    }
+}
+
+/// Retry policy of transaction helpers (e.g [XsTransaction::transact]).
+///
+/// The delay between two attempts starts at `backoff` and is doubled after each
+/// failed attempt, up to `max_backoff`. Async helpers wait with `LocalAsyncXs::sleep`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransactionRetry {
+    /// Maximum number of attempts (including the first one).
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub backoff: Duration,
+    /// Maximum delay between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for TransactionRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 16,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl TransactionRetry {
+    /// Check if a transaction needs to be replayed after failing with `error` at `attempt`.
+    pub fn should_retry(&self, error: &io::Error, attempt: u32) -> bool {
+        XsError::from_io(error).and_then(XsError::errno) == Some(&XsErrno::EAGAIN)
+            && attempt < self.max_attempts
+    }
+
+    /// Delay to wait after the failure of `attempt` (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// Runtime agnostic sleep, the delay elapses in a helper thread.
+#[cfg(feature = "async")]
+async fn thread_sleep(duration: Duration) {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+
+    thread::spawn(move || {
+        thread::sleep(duration);
+        sender.send(()).ok();
+    });
+
+    receiver.await.ok();
+}
+
 /// [`Xs`] async variant.
 #[cfg(feature = "async")]
 #[trait_variant::make(AsyncXs: Send)]
@@ -65,32 +296,217 @@ pub trait LocalAsyncXs {
     async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>>;
 
     /// Read a node.
-    async fn read(&self, path: &str) -> io::Result<Box<str>>;
+    fn read(&self, path: &str) -> impl Future<Output = io::Result<Box<str>>> {
+        let bytes = self.read_bytes(path);
+
+        async move {
+            String::from_utf8(bytes.await?.into_vec())
+                .map(String::into_boxed_str)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    /// Read a node as raw bytes.
+    async fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>>;
 
     /// Write a node.
-    async fn write(&self, path: &str, data: &str) -> io::Result<()>;
+    fn write(&self, path: &str, data: &str) -> impl Future<Output = io::Result<()>> {
+        self.write_bytes(path, data.as_bytes())
+    }
+
+    /// Write raw bytes to a node.
+    async fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()>;
 
     /// Remove a node.
     async fn rm(&self, path: &str) -> io::Result<()>;
+
+    /// Create a directory node (with an empty value).
+    ///
+    /// Alike `mkdir -p`, missing parents are created and it is not an error
+    /// if the node already exists (its value is left unchanged).
+    async fn mkdir(&self, path: &str) -> io::Result<()>;
+
+    /// Get the permissions of a node.
+    async fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>>;
+
+    /// Set the permissions of a node.
+    ///
+    /// The first permission defines the owner of the node and the access of
+    /// the domains that are not listed afterwards.
+    async fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()>;
+
+    /// Get the home path of a domain (e.g `/local/domain/<domid>`).
+    async fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>>;
+
+    /// Get the id of the local domain, read from its relative `domid` node.
+    fn get_domid(&self) -> impl Future<Output = io::Result<u16>> {
+        let domid = self.read("domid");
+
+        async move {
+            domid
+                .await?
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    /// Get the home path of the local domain.
+    fn get_home_path(&self) -> impl Future<Output = io::Result<Box<str>>>
+    where
+        Self: Sync,
+    {
+        async move { self.get_domain_path(self.get_domid().await?).await }
+    }
+
+    /// Wait for `duration` (e.g transaction backoff).
+    ///
+    /// Being runtime agnostic, the default spawns a thread for each call,
+    /// implementations should use the timer of their runtime instead.
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        thread_sleep(duration)
+    }
 }
 
 /// [`XsTransaction`] async variant.
 #[cfg(feature = "async")]
 #[trait_variant::make(AsyncXsTransaction: Send)]
 pub trait LocalAsyncXsTransaction: AsyncXs {
-    type Span: Xs;
+    type Span: AsyncXs + AsyncXsTransactionSpan;
 
     async fn transaction(&self) -> io::Result<Self::Span>;
+
+    /// Run `f` inside a transaction and commit it, replaying the whole transaction
+    /// if it conflicts with another one (EAGAIN) using [TransactionRetry::default].
+    ///
+    /// If `f` fails, the transaction is aborted and the error is returned as is.
+    ///
+    /// The future of `f` borrows the transaction, thus is boxed
+    /// (e.g `|span| Box::pin(async move { span.read("/a").await })`).
+    fn transact<T, F>(&self, f: F) -> impl Future<Output = io::Result<T>>
+    where
+        Self: Sync,
+        Self::Span: Send + Sync,
+        T: Send,
+        F: FnMut(&Self::Span) -> BoxFuture<'_, io::Result<T>> + Send,
+    {
+        self.transact_with(TransactionRetry::default(), f)
+    }
+
+    /// [LocalAsyncXsTransaction::transact] with a custom retry policy.
+    fn transact_with<T, F>(
+        &self,
+        retry: TransactionRetry,
+        f: F,
+    ) -> impl Future<Output = io::Result<T>>
+    where
+        Self: Sync,
+        Self::Span: Send + Sync,
+        T: Send,
+        F: FnMut(&Self::Span) -> BoxFuture<'_, io::Result<T>> + Send,
+    {
+        let mut f = f;
+
+        async move {
+            let mut attempt = 1;
+
+            loop {
+                let span = self.transaction().await?;
+                let value = f(&span).await?;
+
+                match AsyncXsTransactionSpan::commit(span).await {
+                    Err(e) if retry.should_retry(&e, attempt) => {
+                        AsyncXs::sleep(self, retry.backoff(attempt)).await;
+                        attempt += 1;
+                    }
+                    result => return result.map(|_| value),
+                }
+            }
+        }
+    }
 }
 
 /// [`XsTransactionSpan`] async variant.
 #[cfg(feature = "async")]
 #[trait_variant::make(AsyncXsTransactionSpan: Send)]
-pub trait LocalAsyncXsTransactionSpan: Xs {
+pub trait LocalAsyncXsTransactionSpan: AsyncXs {
     /// Commit a transaction.
     async fn commit(self) -> io::Result<()>;
 }
 
+/// [`XsDomainControl`] async variant.
+#[cfg(feature = "async")]
+#[trait_variant::make(AsyncXsDomainControl: Send)]
+pub trait LocalAsyncXsDomainControl {
+    /// Introduce a domain to xenstore, providing the frame number of its
+    /// xenstore ring page (mfn, or gfn for HVM guests) and its event channel.
+    async fn introduce(&self, domid: u16, mfn: u64, evtchn: u32) -> io::Result<()>;
+
+    /// Release a domain (e.g when it is destroyed).
+    async fn release(&self, domid: u16) -> io::Result<()>;
+
+    /// Check if a domain has been introduced.
+    async fn is_domain_introduced(&self, domid: u16) -> io::Result<bool>;
+
+    /// Resume a domain after it has been suspended.
+    async fn resume(&self, domid: u16) -> io::Result<()>;
+
+    /// Make `target` the target of `domid` (e.g device model stubdomain),
+    /// giving `domid` the same privileges as `target`.
+    async fn set_target(&self, domid: u16, target: u16) -> io::Result<()>;
+
+    /// Get the features supported by xenstored, or the ones enabled for domain `domid`
+    /// (see `XENSTORE_SERVER_FEATURE_*` in [crate::protocol]).
+    async fn get_features(&self, domid: Option<u16>) -> io::Result<u32>;
+
+    /// Set the features enabled for domain `domid`.
+    async fn set_features(&self, domid: u16, features: u32) -> io::Result<()>;
+
+    /// List the names of the quotas supported by xenstored.
+    async fn quota_names(&self) -> io::Result<Vec<Box<str>>>;
+
+    /// Get the value of a quota, either the global one or the one of domain `domid`.
+    async fn get_quota(&self, domid: Option<u16>, quota: &str) -> io::Result<u32>;
+
+    /// Set the value of a quota, either the global one or the one of domain `domid`.
+    async fn set_quota(&self, domid: Option<u16>, quota: &str, value: u32) -> io::Result<()>;
+}
+
+/// [`XsControl`] async variant.
+#[cfg(feature = "async")]
+#[trait_variant::make(AsyncXsControl: Send)]
+pub trait LocalAsyncXsControl: AsyncXs {
+    /// Run a control command.
+    async fn control<C: XsControlCommand + Sync>(&self, command: &C) -> io::Result<C::Output>;
+
+    /// Live update xenstored, see [XsControl::live_update].
+    fn live_update(&self, update: &LiveUpdate<'_>) -> impl Future<Output = io::Result<()>>
+    where
+        Self: Sync,
+    {
+        async move {
+            let result = async {
+                for command in update.prepare() {
+                    self.control(&command).await?;
+                }
+
+                while self.control(&update.start()).await? == LiveUpdateStatus::Busy {
+                    AsyncXs::sleep(self, LIVE_UPDATE_POLL).await;
+                }
+
+                Ok(())
+            }
+            .await;
+
+            if result.is_err() {
+                // Don't leave a partial update behind, the original error is more relevant.
+                self.control(&LiveUpdateCommand::Abort).await.ok();
+            }
+
+            result
+        }
+    }
+}
+
 /// Xenstore watch capability trait.
 #[cfg(feature = "async")]
 #[trait_variant::make(AsyncWatch: Send)]
@@ -0,0 +1,1078 @@
+//! In-memory xenstore engine.
+//!
+//! Holds the node tree, the transactions, the watches and the introduced domains,
+//! and implements the xenstore operations over them on behalf of connections.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use crate::{protocol::XENSTORE_PAYLOAD_MAX, XsAccess, XsErrno, XsPermission};
+
+/// Maximum length of an absolute node path.
+const PATH_MAX: usize = 3072;
+
+pub type ConnectionId = u64;
+
+/// Watch events sink of a connection, called with the path and the token.
+pub type EventSink = Box<dyn Fn(&str, &str) + Send>;
+
+#[derive(Clone, Debug)]
+struct Node {
+    value: Vec<u8>,
+    perms: Vec<XsPermission>,
+    /// Changed on every modification of the node (including its children list).
+    generation: u64,
+}
+
+struct Watch {
+    /// Path as given by the watcher (may be relative).
+    path: Box<str>,
+    /// Absolute path (or special path e.g `@introduceDomain`).
+    absolute: Box<str>,
+    token: Box<str>,
+}
+
+struct Connection {
+    domid: u16,
+    watches: Vec<Watch>,
+    sink: EventSink,
+}
+
+/// Modification of a node to report to watchers.
+struct Event {
+    path: Box<str>,
+    /// Permissions of the node (None for special events).
+    perms: Option<Vec<XsPermission>>,
+    /// Also report to watchers of subnodes (node removed).
+    recurse: bool,
+}
+
+struct Transaction {
+    connection: ConnectionId,
+    /// Snapshot of the nodes, modified by the transaction.
+    nodes: BTreeMap<Box<str>, Node>,
+    log: TransactionLog,
+    events: Vec<Event>,
+}
+
+/// Nodes accessed and modified by a transaction.
+#[derive(Default)]
+struct TransactionLog {
+    /// Generation of the nodes (None if absent) accessed by the transaction,
+    /// as it was when the transaction started.
+    accessed: HashMap<Box<str>, Option<u64>>,
+    modified: BTreeSet<Box<str>>,
+}
+
+/// Features supported by the store (see [crate::protocol::XENSTORE_SERVER_FEATURE_RECONNECTION]),
+/// none as there are no rings.
+const SUPPORTED_FEATURES: u32 = 0;
+
+struct Domain {
+    target: Option<u16>,
+    features: u32,
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(index) => &path[..index],
+    }
+}
+
+/// Get the prefix of the paths of the subnodes of `path`.
+fn subnode_prefix(path: &str) -> String {
+    if path == "/" {
+        path.to_string()
+    } else {
+        format!("{path}/")
+    }
+}
+
+fn is_subnode(path: &str, ancestor: &str) -> bool {
+    path.starts_with(&subnode_prefix(ancestor)) && path != ancestor
+}
+
+/// Get the home path of a domain.
+pub fn domain_path(domid: u16) -> String {
+    format!("/local/domain/{domid}")
+}
+
+/// Get the access granted to `domid` (and its `target`) by `perms`.
+fn access(domid: u16, target: Option<u16>, perms: &[XsPermission]) -> XsAccess {
+    // dom0 is privileged.
+    if domid == 0 {
+        return XsAccess::Both;
+    }
+
+    let Some(owner) = perms.first() else {
+        return XsAccess::None;
+    };
+
+    if owner.domid == domid || Some(owner.domid) == target {
+        return XsAccess::Both;
+    }
+
+    perms[1..]
+        .iter()
+        .find(|perm| perm.domid == domid)
+        .map_or(owner.access, |perm| perm.access)
+}
+
+/// Nodes being operated on, either the store nodes or a transaction snapshot.
+struct View<'a> {
+    nodes: &'a mut BTreeMap<Box<str>, Node>,
+    generation: &'a mut u64,
+    log: Option<&'a mut TransactionLog>,
+    events: &'a mut Vec<Event>,
+    domid: u16,
+    target: Option<u16>,
+}
+
+impl View<'_> {
+    /// Record the access of a node (for transaction conflict detection).
+    fn touch(&mut self, path: &str) {
+        if let Some(log) = &mut self.log {
+            if !log.accessed.contains_key(path) {
+                let generation = self.nodes.get(path).map(|node| node.generation);
+                log.accessed.insert(path.into(), generation);
+            }
+        }
+    }
+
+    fn node(&mut self, path: &str) -> Result<&Node, XsErrno> {
+        self.touch(path);
+        self.nodes.get(path).ok_or(XsErrno::ENOENT)
+    }
+
+    fn check_write(&self, node: &Node) -> Result<(), XsErrno> {
+        if access(self.domid, self.target, &node.perms).can_write() {
+            Ok(())
+        } else {
+            Err(XsErrno::EACCES)
+        }
+    }
+
+    /// Get a node the domain can read.
+    fn readable(&mut self, path: &str) -> Result<&Node, XsErrno> {
+        self.touch(path);
+        let node = self.nodes.get(path).ok_or(XsErrno::ENOENT)?;
+
+        if access(self.domid, self.target, &node.perms).can_read() {
+            Ok(node)
+        } else {
+            Err(XsErrno::EACCES)
+        }
+    }
+
+    fn next_generation(&mut self) -> u64 {
+        *self.generation += 1;
+        *self.generation
+    }
+
+    fn mark_modified(&mut self, path: &str) {
+        if let Some(log) = &mut self.log {
+            log.modified.insert(path.into());
+        }
+    }
+
+    /// Update a node (or create it), bumping its generation.
+    fn put(&mut self, path: &str, mut node: Node) {
+        self.touch(path);
+        node.generation = self.next_generation();
+        self.nodes.insert(path.into(), node);
+        self.mark_modified(path);
+    }
+
+    /// Bump the generation of a node (e.g its children changed).
+    fn bump(&mut self, path: &str) {
+        self.touch(path);
+        let generation = self.next_generation();
+
+        if let Some(node) = self.nodes.get_mut(path) {
+            node.generation = generation;
+            self.mark_modified(path);
+        }
+    }
+
+    fn children(&self, path: &str) -> Vec<&str> {
+        let prefix = subnode_prefix(path);
+
+        self.nodes
+            .range::<str, _>((Bound::Excluded(path), Bound::Unbounded))
+            .map(|(subpath, _)| &**subpath)
+            .skip_while(|subpath| !subpath.starts_with(&prefix))
+            .take_while(|subpath| subpath.starts_with(&prefix))
+            .map(|subpath| &subpath[prefix.len()..])
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .collect()
+    }
+
+    /// Create a node and its missing parents.
+    fn create(&mut self, path: &str, value: Vec<u8>) -> Result<(), XsErrno> {
+        let mut missing = vec![path];
+        let mut ancestor = parent(path);
+
+        while self.node(ancestor).is_err() {
+            missing.push(ancestor);
+            ancestor = parent(ancestor);
+        }
+
+        let ancestor_node = self.node(ancestor)?.clone();
+        self.check_write(&ancestor_node)?;
+
+        // New nodes inherit permissions of their parent, but are owned by their creator.
+        let mut perms = ancestor_node.perms;
+        if self.domid != 0 {
+            if let Some(owner) = perms.first_mut() {
+                owner.domid = self.domid;
+            }
+        }
+
+        self.bump(ancestor);
+
+        for (i, subpath) in missing.iter().rev().enumerate() {
+            let node = Node {
+                value: if i == missing.len() - 1 {
+                    value.clone()
+                } else {
+                    Vec::new()
+                },
+                perms: perms.clone(),
+                generation: 0,
+            };
+
+            self.put(subpath, node);
+        }
+
+        self.events.push(Event {
+            path: path.into(),
+            perms: Some(perms),
+            recurse: false,
+        });
+
+        Ok(())
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, XsErrno> {
+        Ok(self.readable(path)?.value.clone())
+    }
+
+    fn write(&mut self, path: &str, value: &[u8]) -> Result<(), XsErrno> {
+        let Ok(node) = self.node(path) else {
+            return self.create(path, value.to_vec());
+        };
+
+        let node = node.clone();
+        self.check_write(&node)?;
+
+        self.events.push(Event {
+            path: path.into(),
+            perms: Some(node.perms.clone()),
+            recurse: false,
+        });
+        self.put(
+            path,
+            Node {
+                value: value.to_vec(),
+                ..node
+            },
+        );
+
+        Ok(())
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), XsErrno> {
+        match self.node(path) {
+            // Existing node is left as is, but must still be writable.
+            Ok(node) => {
+                let node = node.clone();
+                self.check_write(&node)
+            }
+            Err(_) => self.create(path, Vec::new()),
+        }
+    }
+
+    fn rm(&mut self, path: &str) -> Result<(), XsErrno> {
+        if path == "/" {
+            return Err(XsErrno::EINVAL);
+        }
+
+        let Ok(node) = self.node(path) else {
+            // Removing a missing node is fine as long as its parent exists.
+            return self.node(parent(path)).map(|_| ());
+        };
+
+        let node = node.clone();
+        self.check_write(&node)?;
+
+        let prefix = subnode_prefix(path);
+        let subpaths: Vec<Box<str>> = self
+            .nodes
+            .range::<str, _>((Bound::Excluded(path), Bound::Unbounded))
+            .map(|(subpath, _)| subpath.clone())
+            .skip_while(|subpath| !subpath.starts_with(&prefix))
+            .take_while(|subpath| subpath.starts_with(&prefix))
+            .collect();
+
+        for subpath in subpaths.iter().map(|subpath| &**subpath).chain([path]) {
+            self.touch(subpath);
+            self.nodes.remove(subpath);
+            self.mark_modified(subpath);
+        }
+
+        self.bump(parent(path));
+
+        self.events.push(Event {
+            path: path.into(),
+            perms: Some(node.perms),
+            recurse: true,
+        });
+
+        Ok(())
+    }
+
+    fn directory(&mut self, path: &str) -> Result<Vec<Box<str>>, XsErrno> {
+        self.readable(path)?;
+
+        let children: Vec<Box<str>> = self.children(path).into_iter().map(Box::from).collect();
+
+        // Listing must fit in a message, otherwise DIRECTORY_PART must be used.
+        if children.iter().map(|name| name.len() + 1).sum::<usize>() > XENSTORE_PAYLOAD_MAX {
+            return Err(XsErrno::E2BIG);
+        }
+
+        Ok(children)
+    }
+
+    /// Get the generation count and the chunk of the children list starting at `offset`.
+    fn directory_part(&mut self, path: &str, offset: usize) -> Result<(u64, Vec<u8>), XsErrno> {
+        let generation = self.readable(path)?.generation;
+
+        let mut children = Vec::new();
+        for name in self.children(path) {
+            children.extend_from_slice(name.as_bytes());
+            children.push(0);
+        }
+
+        if offset > children.len() {
+            return Err(XsErrno::EINVAL);
+        }
+
+        let max_len = XENSTORE_PAYLOAD_MAX - generation.to_string().len() - 2;
+        let mut chunk = Vec::new();
+
+        // Only send whole names.
+        for name in children[offset..].split_inclusive(|&c| c == 0) {
+            if chunk.len() + name.len() > max_len {
+                return Ok((generation, chunk));
+            }
+
+            chunk.extend_from_slice(name);
+        }
+
+        // End of the list is marked by an empty name.
+        chunk.push(0);
+
+        Ok((generation, chunk))
+    }
+
+    fn get_perms(&mut self, path: &str) -> Result<Vec<XsPermission>, XsErrno> {
+        Ok(self.readable(path)?.perms.clone())
+    }
+
+    fn set_perms(&mut self, path: &str, perms: &[XsPermission]) -> Result<(), XsErrno> {
+        if perms.is_empty() {
+            return Err(XsErrno::EINVAL);
+        }
+
+        let node = self.node(path)?.clone();
+
+        // Only the owner can change permissions.
+        let owner = node.perms.first().map(|perm| perm.domid);
+        if self.domid != 0 && owner != Some(self.domid) && owner != self.target {
+            return Err(XsErrno::EACCES);
+        }
+
+        self.events.push(Event {
+            path: path.into(),
+            perms: Some(perms.to_vec()),
+            recurse: false,
+        });
+        self.put(
+            path,
+            Node {
+                perms: perms.to_vec(),
+                ..node
+            },
+        );
+
+        Ok(())
+    }
+}
+
+/// Operations on nodes.
+pub enum NodeOperation<'a> {
+    Read,
+    Write(&'a [u8]),
+    Mkdir,
+    Rm,
+    Directory,
+    DirectoryPart(usize),
+    GetPerms,
+    SetPerms(&'a [XsPermission]),
+}
+
+/// Result of a [NodeOperation].
+pub enum NodeResult {
+    Done,
+    Value(Vec<u8>),
+    List(Vec<Box<str>>),
+    DirectoryPart(u64, Vec<u8>),
+    Perms(Vec<XsPermission>),
+}
+
+pub struct Engine {
+    nodes: BTreeMap<Box<str>, Node>,
+    generation: u64,
+    connections: HashMap<ConnectionId, Connection>,
+    transactions: HashMap<u32, Transaction>,
+    domains: HashMap<u16, Domain>,
+    next_connection: ConnectionId,
+    next_transaction: u32,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        let root = Node {
+            value: Vec::new(),
+            perms: vec![XsPermission {
+                domid: 0,
+                access: XsAccess::None,
+            }],
+            generation: 0,
+        };
+
+        Self {
+            nodes: BTreeMap::from([("/".into(), root)]),
+            generation: 0,
+            connections: HashMap::new(),
+            transactions: HashMap::new(),
+            domains: HashMap::new(),
+            next_connection: 0,
+            next_transaction: 0,
+        }
+    }
+}
+
+impl Engine {
+    /// Add a connection of domain `domid`, its watch events are given to `sink`.
+    pub fn connect(&mut self, domid: u16, sink: EventSink) -> ConnectionId {
+        self.next_connection += 1;
+
+        self.connections.insert(
+            self.next_connection,
+            Connection {
+                domid,
+                watches: Vec::new(),
+                sink,
+            },
+        );
+
+        self.next_connection
+    }
+
+    /// Remove a connection with its watches and transactions.
+    pub fn disconnect(&mut self, connection: ConnectionId) {
+        self.connections.remove(&connection);
+        self.transactions
+            .retain(|_, transaction| transaction.connection != connection);
+    }
+
+    fn connection(&self, connection: ConnectionId) -> Result<&Connection, XsErrno> {
+        self.connections.get(&connection).ok_or(XsErrno::EINVAL)
+    }
+
+    fn domid(&self, connection: ConnectionId) -> Result<u16, XsErrno> {
+        Ok(self.connection(connection)?.domid)
+    }
+
+    fn check_privileged(&self, connection: ConnectionId) -> Result<(), XsErrno> {
+        match self.domid(connection)? {
+            0 => Ok(()),
+            _ => Err(XsErrno::EACCES),
+        }
+    }
+
+    /// Get the absolute path of `path` (relative paths are relative to the
+    /// home path of `domid`).
+    pub fn canonicalize(domid: u16, path: &str, special: bool) -> Result<Box<str>, XsErrno> {
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || "-/_@".contains(c);
+
+        let absolute: Box<str> = if path.starts_with('/') || (special && path.starts_with('@')) {
+            path.into()
+        } else {
+            format!("{}/{path}", domain_path(domid)).into()
+        };
+
+        let valid = !path.is_empty()
+            && absolute.len() <= PATH_MAX
+            && absolute.chars().all(valid_char)
+            && !absolute.contains("//")
+            && (absolute.starts_with('@') || &*absolute == "/" || !absolute.ends_with('/'));
+
+        if valid {
+            Ok(absolute)
+        } else {
+            Err(XsErrno::EINVAL)
+        }
+    }
+
+    /// Make an operation on a node, within transaction `tx_id` (if not 0).
+    pub fn node_operation(
+        &mut self,
+        connection: ConnectionId,
+        tx_id: u32,
+        path: &str,
+        operation: NodeOperation<'_>,
+    ) -> Result<NodeResult, XsErrno> {
+        let domid = self.domid(connection)?;
+        let target = self.domains.get(&domid).and_then(|domain| domain.target);
+        let path = Self::canonicalize(domid, path, false)?;
+
+        let mut events = Vec::new();
+        let mut view = if tx_id == 0 {
+            View {
+                nodes: &mut self.nodes,
+                generation: &mut self.generation,
+                log: None,
+                events: &mut events,
+                domid,
+                target,
+            }
+        } else {
+            let transaction = self
+                .transactions
+                .get_mut(&tx_id)
+                .filter(|transaction| transaction.connection == connection)
+                .ok_or(XsErrno::ENOENT)?;
+
+            View {
+                nodes: &mut transaction.nodes,
+                generation: &mut self.generation,
+                log: Some(&mut transaction.log),
+                events: &mut transaction.events,
+                domid,
+                target,
+            }
+        };
+
+        let result = match operation {
+            NodeOperation::Read => view.read(&path).map(NodeResult::Value),
+            NodeOperation::Write(value) => view.write(&path, value).map(|_| NodeResult::Done),
+            NodeOperation::Mkdir => view.mkdir(&path).map(|_| NodeResult::Done),
+            NodeOperation::Rm => view.rm(&path).map(|_| NodeResult::Done),
+            NodeOperation::Directory => view.directory(&path).map(NodeResult::List),
+            NodeOperation::DirectoryPart(offset) => view
+                .directory_part(&path, offset)
+                .map(|(generation, chunk)| NodeResult::DirectoryPart(generation, chunk)),
+            NodeOperation::GetPerms => view.get_perms(&path).map(NodeResult::Perms),
+            NodeOperation::SetPerms(perms) => {
+                view.set_perms(&path, perms).map(|_| NodeResult::Done)
+            }
+        };
+
+        // Events of transactions are fired when commited.
+        self.fire(events);
+
+        result
+    }
+
+    pub fn transaction_start(&mut self, connection: ConnectionId) -> Result<u32, XsErrno> {
+        self.connection(connection)?;
+
+        // Transaction ids are never 0 (no transaction).
+        self.next_transaction = self.next_transaction.checked_add(1).unwrap_or(1);
+
+        self.transactions.insert(
+            self.next_transaction,
+            Transaction {
+                connection,
+                nodes: self.nodes.clone(),
+                log: TransactionLog::default(),
+                events: Vec::new(),
+            },
+        );
+
+        Ok(self.next_transaction)
+    }
+
+    pub fn transaction_end(
+        &mut self,
+        connection: ConnectionId,
+        tx_id: u32,
+        commit: bool,
+    ) -> Result<(), XsErrno> {
+        // Check the owner before removing, other connections must not end it.
+        match self.transactions.get(&tx_id) {
+            Some(transaction) if transaction.connection == connection => {}
+            _ => return Err(XsErrno::ENOENT),
+        }
+
+        let transaction = self.transactions.remove(&tx_id).ok_or(XsErrno::ENOENT)?;
+
+        if !commit {
+            return Ok(());
+        }
+
+        // Conflicts if any node accessed by the transaction has been modified meanwhile.
+        let conflict = transaction.log.accessed.iter().any(|(path, generation)| {
+            self.nodes.get(path).map(|node| node.generation) != *generation
+        });
+
+        if conflict {
+            return Err(XsErrno::EAGAIN);
+        }
+
+        for path in transaction.log.modified {
+            match transaction.nodes.get(&path) {
+                Some(node) => self.nodes.insert(path, node.clone()),
+                None => self.nodes.remove(&path),
+            };
+        }
+
+        self.fire(transaction.events);
+
+        Ok(())
+    }
+
+    /// Register a watch.
+    ///
+    /// Watches fire once registered, it is up to the caller to deliver this
+    /// first event (after acknowledging the watch).
+    pub fn watch(
+        &mut self,
+        connection: ConnectionId,
+        path: &str,
+        token: &str,
+    ) -> Result<(), XsErrno> {
+        let domid = self.domid(connection)?;
+        let absolute = Self::canonicalize(domid, path, true)?;
+
+        let Some(connection) = self.connections.get_mut(&connection) else {
+            return Err(XsErrno::EINVAL);
+        };
+
+        if connection
+            .watches
+            .iter()
+            .any(|watch| *watch.path == *path && *watch.token == *token)
+        {
+            return Err(XsErrno::EEXIST);
+        }
+
+        connection.watches.push(Watch {
+            path: path.into(),
+            absolute,
+            token: token.into(),
+        });
+
+        Ok(())
+    }
+
+    pub fn unwatch(
+        &mut self,
+        connection: ConnectionId,
+        path: &str,
+        token: &str,
+    ) -> Result<(), XsErrno> {
+        let Some(connection) = self.connections.get_mut(&connection) else {
+            return Err(XsErrno::EINVAL);
+        };
+
+        let index = connection
+            .watches
+            .iter()
+            .position(|watch| *watch.path == *path && *watch.token == *token)
+            .ok_or(XsErrno::ENOENT)?;
+
+        connection.watches.remove(index);
+
+        Ok(())
+    }
+
+    /// Remove all the watches and transactions of a connection.
+    pub fn reset_watches(&mut self, connection: ConnectionId) -> Result<(), XsErrno> {
+        let Some(conn) = self.connections.get_mut(&connection) else {
+            return Err(XsErrno::EINVAL);
+        };
+
+        conn.watches.clear();
+        self.transactions
+            .retain(|_, transaction| transaction.connection != connection);
+
+        Ok(())
+    }
+
+    fn fire(&mut self, events: Vec<Event>) {
+        for event in events {
+            for connection in self.connections.values() {
+                let target = self
+                    .domains
+                    .get(&connection.domid)
+                    .and_then(|domain| domain.target);
+
+                // Watchers must be able to read the node.
+                if let Some(perms) = &event.perms {
+                    if !access(connection.domid, target, perms).can_read() {
+                        continue;
+                    }
+                }
+
+                let home = subnode_prefix(&domain_path(connection.domid));
+
+                for watch in &connection.watches {
+                    let path = if *watch.absolute == *event.path
+                        || (event.perms.is_some() && is_subnode(&event.path, &watch.absolute))
+                    {
+                        // Report paths relatively to relative watches.
+                        match event.path.strip_prefix(&home) {
+                            Some(relative) if !watch.path.starts_with('/') => relative,
+                            _ => &event.path,
+                        }
+                    } else if event.recurse && is_subnode(&watch.absolute, &event.path) {
+                        &watch.path
+                    } else {
+                        continue;
+                    };
+
+                    (connection.sink)(path, &watch.token);
+                }
+            }
+        }
+    }
+
+    fn special_event(&mut self, path: &str) {
+        self.fire(vec![Event {
+            path: path.into(),
+            perms: None,
+            recurse: false,
+        }]);
+    }
+
+    pub fn introduce(
+        &mut self,
+        connection: ConnectionId,
+        domid: u16,
+        _mfn: u64,
+        _evtchn: u32,
+    ) -> Result<(), XsErrno> {
+        self.check_privileged(connection)?;
+
+        if self.domains.contains_key(&domid) {
+            return Ok(());
+        }
+
+        self.domains.insert(
+            domid,
+            Domain {
+                target: None,
+                features: 0,
+            },
+        );
+        self.special_event("@introduceDomain");
+
+        Ok(())
+    }
+
+    pub fn release(&mut self, connection: ConnectionId, domid: u16) -> Result<(), XsErrno> {
+        self.check_privileged(connection)?;
+
+        self.domains.remove(&domid).ok_or(XsErrno::ENOENT)?;
+        self.special_event("@releaseDomain");
+
+        Ok(())
+    }
+
+    pub fn is_domain_introduced(
+        &self,
+        connection: ConnectionId,
+        domid: u16,
+    ) -> Result<bool, XsErrno> {
+        self.check_privileged(connection)?;
+
+        Ok(self.domains.contains_key(&domid))
+    }
+
+    pub fn resume(&mut self, connection: ConnectionId, domid: u16) -> Result<(), XsErrno> {
+        self.check_privileged(connection)?;
+
+        self.domains.get(&domid).map(|_| ()).ok_or(XsErrno::ENOENT)
+    }
+
+    pub fn set_target(
+        &mut self,
+        connection: ConnectionId,
+        domid: u16,
+        target: u16,
+    ) -> Result<(), XsErrno> {
+        self.check_privileged(connection)?;
+
+        if !self.domains.contains_key(&target) {
+            return Err(XsErrno::ENOENT);
+        }
+
+        let domain = self.domains.get_mut(&domid).ok_or(XsErrno::ENOENT)?;
+        domain.target = Some(target);
+
+        Ok(())
+    }
+
+    /// Get the features of the store (none), or the ones set for domain `domid`.
+    pub fn get_features(
+        &self,
+        connection: ConnectionId,
+        domid: Option<u16>,
+    ) -> Result<u32, XsErrno> {
+        let Some(domid) = domid else {
+            return Ok(SUPPORTED_FEATURES);
+        };
+
+        self.check_privileged(connection)?;
+
+        self.domains
+            .get(&domid)
+            .map(|domain| domain.features)
+            .ok_or(XsErrno::ENOENT)
+    }
+
+    pub fn set_features(
+        &mut self,
+        connection: ConnectionId,
+        domid: u16,
+        features: u32,
+    ) -> Result<(), XsErrno> {
+        self.check_privileged(connection)?;
+
+        if features & !SUPPORTED_FEATURES != 0 {
+            return Err(XsErrno::EINVAL);
+        }
+
+        let domain = self.domains.get_mut(&domid).ok_or(XsErrno::ENOENT)?;
+        domain.features = features;
+
+        Ok(())
+    }
+
+    /// Get the names of the quotas (none, quotas aren't supported).
+    pub fn quota_names(&self, connection: ConnectionId) -> Result<Vec<Box<str>>, XsErrno> {
+        self.check_privileged(connection)?;
+
+        Ok(Vec::new())
+    }
+
+    pub fn get_quota(
+        &self,
+        connection: ConnectionId,
+        domid: Option<u16>,
+        _quota: &str,
+    ) -> Result<u32, XsErrno> {
+        self.check_quota(connection, domid)
+            .and(Err(XsErrno::EINVAL))
+    }
+
+    pub fn set_quota(
+        &mut self,
+        connection: ConnectionId,
+        domid: Option<u16>,
+        _quota: &str,
+        _value: u32,
+    ) -> Result<(), XsErrno> {
+        self.check_quota(connection, domid)
+            .and(Err(XsErrno::EINVAL))
+    }
+
+    /// Check a quota access, the quota itself is always unknown.
+    fn check_quota(&self, connection: ConnectionId, domid: Option<u16>) -> Result<(), XsErrno> {
+        self.check_privileged(connection)?;
+
+        match domid {
+            Some(domid) if !self.domains.contains_key(&domid) => Err(XsErrno::ENOENT),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(engine: &mut Engine, domid: u16) -> ConnectionId {
+        engine.connect(domid, Box::new(|_, _| {}))
+    }
+
+    fn write(engine: &mut Engine, connection: ConnectionId, tx_id: u32, path: &str, value: &str) {
+        engine
+            .node_operation(
+                connection,
+                tx_id,
+                path,
+                NodeOperation::Write(value.as_bytes()),
+            )
+            .unwrap();
+    }
+
+    fn read(
+        engine: &mut Engine,
+        connection: ConnectionId,
+        tx_id: u32,
+        path: &str,
+    ) -> Result<Vec<u8>, XsErrno> {
+        match engine.node_operation(connection, tx_id, path, NodeOperation::Read)? {
+            NodeResult::Value(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+
+    fn directory_part(
+        engine: &mut Engine,
+        connection: ConnectionId,
+        path: &str,
+        offset: usize,
+    ) -> (u64, Vec<u8>) {
+        match engine.node_operation(connection, 0, path, NodeOperation::DirectoryPart(offset)) {
+            Ok(NodeResult::DirectoryPart(generation, chunk)) => (generation, chunk),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Create enough children of `/dir` for their listing not to fit in a message.
+    fn large_directory(engine: &mut Engine, connection: ConnectionId) -> Vec<String> {
+        let names: Vec<String> = (0..100).map(|i| format!("{i:0>60}")).collect();
+
+        for name in &names {
+            write(engine, connection, 0, &format!("/dir/{name}"), "");
+        }
+
+        names
+    }
+
+    #[test]
+    fn transaction_end_other_connection() {
+        let mut engine = Engine::default();
+        let owner = connect(&mut engine, 0);
+        let other = connect(&mut engine, 0);
+
+        let tx_id = engine.transaction_start(owner).unwrap();
+        write(&mut engine, owner, tx_id, "/a", "1");
+
+        // Neither committing nor aborting it is allowed.
+        assert_eq!(
+            engine.transaction_end(other, tx_id, true),
+            Err(XsErrno::ENOENT)
+        );
+        assert_eq!(
+            engine.transaction_end(other, tx_id, false),
+            Err(XsErrno::ENOENT)
+        );
+
+        // Transaction is left untouched for its owner.
+        write(&mut engine, owner, tx_id, "/b", "2");
+        engine.transaction_end(owner, tx_id, true).unwrap();
+        assert_eq!(read(&mut engine, other, 0, "/a"), Ok(b"1".to_vec()));
+        assert_eq!(read(&mut engine, other, 0, "/b"), Ok(b"2".to_vec()));
+
+        assert_eq!(
+            engine.transaction_end(owner, tx_id, true),
+            Err(XsErrno::ENOENT)
+        );
+    }
+
+    #[test]
+    fn transaction_other_connection() {
+        let mut engine = Engine::default();
+        let owner = connect(&mut engine, 0);
+        let other = connect(&mut engine, 0);
+
+        let tx_id = engine.transaction_start(owner).unwrap();
+
+        assert_eq!(read(&mut engine, other, tx_id, "/"), Err(XsErrno::ENOENT));
+    }
+
+    #[test]
+    fn directory_too_large() {
+        let mut engine = Engine::default();
+        let connection = connect(&mut engine, 0);
+        large_directory(&mut engine, connection);
+
+        assert!(matches!(
+            engine.node_operation(connection, 0, "/dir", NodeOperation::Directory),
+            Err(XsErrno::E2BIG)
+        ));
+    }
+
+    #[test]
+    fn directory_part_chunks() {
+        let mut engine = Engine::default();
+        let connection = connect(&mut engine, 0);
+        let names = large_directory(&mut engine, connection);
+
+        let mut children = Vec::new();
+        let mut chunks = 0;
+        let generation = loop {
+            let (generation, chunk) =
+                directory_part(&mut engine, connection, "/dir", children.len());
+            chunks += 1;
+
+            // Chunk and generation must fit in a message.
+            assert!(chunk.len() + generation.to_string().len() < XENSTORE_PAYLOAD_MAX);
+            // Only whole names are sent.
+            assert_eq!(chunk.last(), Some(&0));
+
+            children.extend_from_slice(&chunk);
+
+            if children.ends_with(&[0, 0]) {
+                break generation;
+            }
+        };
+
+        assert!(chunks > 1);
+
+        let mut expected = names.join("\0").into_bytes();
+        expected.extend_from_slice(b"\0\0");
+        assert_eq!(children, expected);
+
+        // Offset past the end of the listing.
+        assert!(matches!(
+            engine.node_operation(
+                connection,
+                0,
+                "/dir",
+                NodeOperation::DirectoryPart(children.len() + 1)
+            ),
+            Err(XsErrno::EINVAL)
+        ));
+
+        // Generation is kept until the directory is modified.
+        assert_eq!(
+            directory_part(&mut engine, connection, "/dir", 0).0,
+            generation
+        );
+        write(&mut engine, connection, 0, "/dir/new", "");
+        assert_ne!(
+            directory_part(&mut engine, connection, "/dir", 0).0,
+            generation
+        );
+    }
+
+    #[test]
+    fn directory_part_empty() {
+        let mut engine = Engine::default();
+        let connection = connect(&mut engine, 0);
+        write(&mut engine, connection, 0, "/dir", "");
+
+        assert_eq!(directory_part(&mut engine, connection, "/dir", 0).1, b"\0");
+    }
+}
@@ -0,0 +1,862 @@
+//! In-memory Xenstore implementation.
+//!
+//! Implements the xenstore semantics (permissions, transactions, watches, ...)
+//! without a Xen host, e.g for testing code built on [Xs] or [AsyncXs].
+//!
+//! ```
+//! use xenstore_rs::{memory::XsMemory, Xs};
+//!
+//! let xs = XsMemory::new();
+//! xs.write("/local/domain/0/name", "Domain-0")?;
+//! assert_eq!(&*xs.read("/local/domain/0/name")?, "Domain-0");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+pub(crate) mod engine;
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use engine::{ConnectionId, Engine, NodeOperation, NodeResult};
+
+use crate::{
+    protocol::{XsDirectoryPart, XsMessageType},
+    AsyncWatch, AsyncXs, AsyncXsDomainControl, AsyncXsTransaction, AsyncXsTransactionSpan, Xs,
+    XsDomainControl, XsErrno, XsError, XsPermission, XsTransaction, XsTransactionSpan, XsWatch,
+};
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A poisoned lock only means that another thread panicked while using it,
+    // the store itself is left consistent.
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// In-memory xenstore database, shared by its connections.
+#[derive(Clone, Default)]
+pub struct XsMemoryStore {
+    engine: Arc<Mutex<Engine>>,
+}
+
+impl XsMemoryStore {
+    /// Create an empty store (with only the root node).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a new connection to the store on behalf of domain `domid`,
+    /// whose permissions apply to the requests (dom0 is privileged).
+    pub fn connect(&self, domid: u16) -> XsMemory {
+        let watchers: Arc<Mutex<HashMap<Box<str>, WatchSender>>> = Arc::default();
+
+        let sink = {
+            let watchers = watchers.clone();
+
+            Box::new(move |path: &str, token: &str| {
+                if let Some(sender) = lock(&watchers).get(token) {
+                    sender.send(path.into());
+                }
+            })
+        };
+
+        let id = self.engine().connect(domid, sink);
+
+        XsMemory {
+            connection: Arc::new(XsMemoryConnection {
+                store: self.clone(),
+                id,
+                watchers,
+                watch_count: Mutex::new(0),
+            }),
+            tx_id: 0,
+        }
+    }
+
+    pub(crate) fn engine(&self) -> MutexGuard<'_, Engine> {
+        lock(&self.engine)
+    }
+}
+
+enum WatchSender {
+    Blocking(mpsc::Sender<Box<str>>),
+    Async(futures::channel::mpsc::UnboundedSender<Box<str>>),
+}
+
+impl WatchSender {
+    fn send(&self, path: Box<str>) {
+        // Receiver may be gone if its watch is being dropped.
+        match self {
+            WatchSender::Blocking(sender) => sender.send(path).ok(),
+            WatchSender::Async(sender) => sender.unbounded_send(path).ok(),
+        };
+    }
+}
+
+struct XsMemoryConnection {
+    store: XsMemoryStore,
+    id: ConnectionId,
+    /// Watch event senders by token.
+    watchers: Arc<Mutex<HashMap<Box<str>, WatchSender>>>,
+    watch_count: Mutex<u64>,
+}
+
+impl Drop for XsMemoryConnection {
+    fn drop(&mut self) {
+        self.store.engine().disconnect(self.id);
+    }
+}
+
+/// In-memory Xenstore implementation, a connection to a [XsMemoryStore].
+///
+/// Clones share the same connection (and thus its watches and transactions).
+#[derive(Clone)]
+pub struct XsMemory {
+    connection: Arc<XsMemoryConnection>,
+    /// Transaction in which the requests are made (0 if none).
+    tx_id: u32,
+}
+
+impl Default for XsMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl XsMemory {
+    /// Create a new empty store and connect to it as dom0.
+    pub fn new() -> Self {
+        XsMemoryStore::new().connect(0)
+    }
+
+    /// Get the store of this connection (e.g to open connections of other domains).
+    pub fn store(&self) -> &XsMemoryStore {
+        &self.connection.store
+    }
+
+    /// Remove all the watches and transactions of the connection.
+    ///
+    /// This also affects the watches made through this [XsMemory] which will
+    /// no longer receive events and fail (or end for async ones) instead.
+    pub fn reset_watches(&self) -> io::Result<()> {
+        self.call(XsMessageType::ResetWatches, None, |engine, id, _| {
+            engine.reset_watches(id)
+        })?;
+
+        // Disconnect the receivers of the removed watches.
+        lock(&self.connection.watchers).clear();
+        Ok(())
+    }
+
+    /// Make a request to the engine, mapping its errors like xenstored ones.
+    fn call<T>(
+        &self,
+        request: XsMessageType,
+        path: Option<&str>,
+        f: impl FnOnce(&mut Engine, ConnectionId, u32) -> Result<T, XsErrno>,
+    ) -> io::Result<T> {
+        let mut engine = self.connection.store.engine();
+
+        f(&mut engine, self.connection.id, self.tx_id).map_err(|errno| {
+            XsError::Xenstore {
+                errno,
+                request,
+                path: path.map(Box::from),
+            }
+            .into()
+        })
+    }
+
+    fn node_operation(
+        &self,
+        request: XsMessageType,
+        path: &str,
+        operation: NodeOperation<'_>,
+    ) -> io::Result<NodeResult> {
+        self.call(request, Some(path), |engine, id, tx_id| {
+            engine.node_operation(id, tx_id, path, operation)
+        })
+    }
+
+    /// List a directory using DIRECTORY_PART, used when the listing doesn't
+    /// fit in a single message.
+    fn directory_part(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        let mut listing = XsDirectoryPart::new(path);
+
+        loop {
+            let NodeResult::DirectoryPart(generation, chunk) = self.node_operation(
+                XsMessageType::DirectoryPart,
+                path,
+                NodeOperation::DirectoryPart(listing.offset()),
+            )?
+            else {
+                unreachable!("DIRECTORY_PART returns a directory part");
+            };
+
+            if let Some(children) = listing.push(generation, &chunk)? {
+                return Ok(children);
+            }
+        }
+    }
+
+    /// Register a watch with a new token, whose events are given to `sender`.
+    fn watch_with(&self, path: &str, sender: WatchSender) -> io::Result<XsMemoryWatchHandle> {
+        let token: Box<str> = {
+            let mut watch_count = lock(&self.connection.watch_count);
+            *watch_count += 1;
+
+            format!("xs-memory-{watch_count}").into()
+        };
+
+        lock(&self.connection.watchers).insert(token.clone(), sender);
+
+        let handle = XsMemoryWatchHandle {
+            connection: self.connection.clone(),
+            path: path.into(),
+            token,
+        };
+
+        // On failure, handle is dropped and its token unregistered.
+        self.call(XsMessageType::Watch, Some(path), |engine, id, _| {
+            engine.watch(id, path, &handle.token)
+        })?;
+
+        // Watches fire once registered.
+        if let Some(sender) = lock(&self.connection.watchers).get(&handle.token) {
+            sender.send(path.into());
+        }
+
+        Ok(handle)
+    }
+}
+
+impl Xs for XsMemory {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        match self.node_operation(XsMessageType::Directory, path, NodeOperation::Directory) {
+            Ok(NodeResult::List(children)) => Ok(children),
+            // E2BIG means that the directory listing is too long for DIRECTORY.
+            Err(e) if XsError::from_io(&e).and_then(XsError::errno) == Some(&XsErrno::E2BIG) => {
+                self.directory_part(path)
+            }
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("DIRECTORY returns a list"),
+        }
+    }
+
+    fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        match self.node_operation(XsMessageType::Read, path, NodeOperation::Read)? {
+            NodeResult::Value(value) => Ok(value.into()),
+            _ => unreachable!("READ returns a value"),
+        }
+    }
+
+    fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.node_operation(XsMessageType::Write, path, NodeOperation::Write(data))?;
+
+        Ok(())
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        self.node_operation(XsMessageType::Rm, path, NodeOperation::Rm)?;
+
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.node_operation(XsMessageType::Mkdir, path, NodeOperation::Mkdir)?;
+
+        Ok(())
+    }
+
+    fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        match self.node_operation(XsMessageType::GetPerms, path, NodeOperation::GetPerms)? {
+            NodeResult::Perms(perms) => Ok(perms),
+            _ => unreachable!("GET_PERMS returns permissions"),
+        }
+    }
+
+    fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        self.node_operation(
+            XsMessageType::SetPerms,
+            path,
+            NodeOperation::SetPerms(perms),
+        )?;
+
+        Ok(())
+    }
+
+    fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        Ok(engine::domain_path(domid).into())
+    }
+}
+
+impl XsDomainControl for XsMemory {
+    fn introduce(&self, domid: u16, mfn: u64, evtchn: u32) -> io::Result<()> {
+        self.call(XsMessageType::Introduce, None, |engine, id, _| {
+            engine.introduce(id, domid, mfn, evtchn)
+        })
+    }
+
+    fn release(&self, domid: u16) -> io::Result<()> {
+        self.call(XsMessageType::Release, None, |engine, id, _| {
+            engine.release(id, domid)
+        })
+    }
+
+    fn is_domain_introduced(&self, domid: u16) -> io::Result<bool> {
+        self.call(XsMessageType::IsDomainIntroduced, None, |engine, id, _| {
+            engine.is_domain_introduced(id, domid)
+        })
+    }
+
+    fn resume(&self, domid: u16) -> io::Result<()> {
+        self.call(XsMessageType::Resume, None, |engine, id, _| {
+            engine.resume(id, domid)
+        })
+    }
+
+    fn set_target(&self, domid: u16, target: u16) -> io::Result<()> {
+        self.call(XsMessageType::SetTarget, None, |engine, id, _| {
+            engine.set_target(id, domid, target)
+        })
+    }
+
+    fn get_features(&self, domid: Option<u16>) -> io::Result<u32> {
+        self.call(XsMessageType::GetFeature, None, |engine, id, _| {
+            engine.get_features(id, domid)
+        })
+    }
+
+    fn set_features(&self, domid: u16, features: u32) -> io::Result<()> {
+        self.call(XsMessageType::SetFeature, None, |engine, id, _| {
+            engine.set_features(id, domid, features)
+        })
+    }
+
+    fn quota_names(&self) -> io::Result<Vec<Box<str>>> {
+        self.call(XsMessageType::GetQuota, None, |engine, id, _| {
+            engine.quota_names(id)
+        })
+    }
+
+    fn get_quota(&self, domid: Option<u16>, quota: &str) -> io::Result<u32> {
+        self.call(XsMessageType::GetQuota, None, |engine, id, _| {
+            engine.get_quota(id, domid, quota)
+        })
+    }
+
+    fn set_quota(&self, domid: Option<u16>, quota: &str, value: u32) -> io::Result<()> {
+        self.call(XsMessageType::SetQuota, None, |engine, id, _| {
+            engine.set_quota(id, domid, quota, value)
+        })
+    }
+}
+
+impl XsTransaction for XsMemory {
+    type Span = XsMemoryTransaction;
+
+    fn transaction(&self) -> io::Result<XsMemoryTransaction> {
+        let tx_id = self.call(XsMessageType::TransactionStart, None, |engine, id, _| {
+            engine.transaction_start(id)
+        })?;
+
+        Ok(XsMemoryTransaction {
+            xs: XsMemory {
+                connection: self.connection.clone(),
+                tx_id,
+            },
+            finished: false,
+        })
+    }
+}
+
+/// In-memory Xenstore transaction.
+///
+/// Aborted on [Drop] unless commited with [XsTransactionSpan::commit].
+pub struct XsMemoryTransaction {
+    xs: XsMemory,
+    finished: bool,
+}
+
+impl XsMemoryTransaction {
+    fn end(&mut self, commit: bool) -> io::Result<()> {
+        self.finished = true;
+
+        self.xs
+            .call(XsMessageType::TransactionEnd, None, |engine, id, tx_id| {
+                engine.transaction_end(id, tx_id, commit)
+            })
+    }
+}
+
+impl Xs for XsMemoryTransaction {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        Xs::directory(&self.xs, path)
+    }
+
+    fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        Xs::read_bytes(&self.xs, path)
+    }
+
+    fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        Xs::write_bytes(&self.xs, path, data)
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        Xs::rm(&self.xs, path)
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        Xs::mkdir(&self.xs, path)
+    }
+
+    fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        Xs::get_perms(&self.xs, path)
+    }
+
+    fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        Xs::set_perms(&self.xs, path, perms)
+    }
+
+    fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        Xs::get_domain_path(&self.xs, domid)
+    }
+}
+
+impl XsTransactionSpan for XsMemoryTransaction {
+    fn commit(mut self) -> io::Result<()> {
+        self.end(true)
+    }
+}
+
+impl Drop for XsMemoryTransaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            // Can only fail if the transaction is already gone (e.g reset).
+            self.end(false).ok();
+        }
+    }
+}
+
+/// Registered watch, unregistered on [Drop].
+struct XsMemoryWatchHandle {
+    connection: Arc<XsMemoryConnection>,
+    path: Box<str>,
+    token: Box<str>,
+}
+
+impl Drop for XsMemoryWatchHandle {
+    fn drop(&mut self) {
+        // Can only fail if the watch is already gone (e.g reset).
+        self.connection
+            .store
+            .engine()
+            .unwatch(self.connection.id, &self.path, &self.token)
+            .ok();
+
+        lock(&self.connection.watchers).remove(&self.token);
+    }
+}
+
+impl XsWatch for XsMemory {
+    type Watch = XsMemoryWatch;
+
+    fn watch(&self, path: &str) -> io::Result<XsMemoryWatch> {
+        let (sender, receiver) = mpsc::channel();
+        let handle = self.watch_with(path, WatchSender::Blocking(sender))?;
+
+        Ok(XsMemoryWatch {
+            _handle: handle,
+            receiver,
+        })
+    }
+}
+
+/// In-memory blocking watch object.
+///
+/// Yields paths of updated nodes/subnodes (starting with `path` itself).
+pub struct XsMemoryWatch {
+    _handle: XsMemoryWatchHandle,
+    receiver: mpsc::Receiver<Box<str>>,
+}
+
+impl XsMemoryWatch {
+    /// Wait for the next event.
+    ///
+    /// Returns `Ok(None)` if there was no event within `timeout`, waits forever
+    /// if `timeout` is [None].
+    pub fn next_timeout(&mut self, timeout: Option<Duration>) -> io::Result<Option<Box<str>>> {
+        let result = match timeout {
+            Some(timeout) => self.receiver.recv_timeout(timeout),
+            None => self
+                .receiver
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        match result {
+            Ok(path) => Ok(Some(path)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            // Watch has been removed (e.g reset).
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Watch has been removed",
+            )),
+        }
+    }
+}
+
+impl Iterator for XsMemoryWatch {
+    type Item = io::Result<Box<str>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_timeout(None).transpose()
+    }
+}
+
+impl AsyncXs for XsMemory {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        Xs::directory(self, path)
+    }
+
+    async fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        Xs::read_bytes(self, path)
+    }
+
+    async fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        Xs::write_bytes(self, path, data)
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        Xs::rm(self, path)
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        Xs::mkdir(self, path)
+    }
+
+    async fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        Xs::get_perms(self, path)
+    }
+
+    async fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        Xs::set_perms(self, path, perms)
+    }
+
+    async fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        Xs::get_domain_path(self, domid)
+    }
+}
+
+impl AsyncXsDomainControl for XsMemory {
+    async fn introduce(&self, domid: u16, mfn: u64, evtchn: u32) -> io::Result<()> {
+        XsDomainControl::introduce(self, domid, mfn, evtchn)
+    }
+
+    async fn release(&self, domid: u16) -> io::Result<()> {
+        XsDomainControl::release(self, domid)
+    }
+
+    async fn is_domain_introduced(&self, domid: u16) -> io::Result<bool> {
+        XsDomainControl::is_domain_introduced(self, domid)
+    }
+
+    async fn resume(&self, domid: u16) -> io::Result<()> {
+        XsDomainControl::resume(self, domid)
+    }
+
+    async fn set_target(&self, domid: u16, target: u16) -> io::Result<()> {
+        XsDomainControl::set_target(self, domid, target)
+    }
+
+    async fn get_features(&self, domid: Option<u16>) -> io::Result<u32> {
+        XsDomainControl::get_features(self, domid)
+    }
+
+    async fn set_features(&self, domid: u16, features: u32) -> io::Result<()> {
+        XsDomainControl::set_features(self, domid, features)
+    }
+
+    async fn quota_names(&self) -> io::Result<Vec<Box<str>>> {
+        XsDomainControl::quota_names(self)
+    }
+
+    async fn get_quota(&self, domid: Option<u16>, quota: &str) -> io::Result<u32> {
+        XsDomainControl::get_quota(self, domid, quota)
+    }
+
+    async fn set_quota(&self, domid: Option<u16>, quota: &str, value: u32) -> io::Result<()> {
+        XsDomainControl::set_quota(self, domid, quota, value)
+    }
+}
+
+impl AsyncXsTransaction for XsMemory {
+    type Span = XsMemoryTransaction;
+
+    async fn transaction(&self) -> io::Result<XsMemoryTransaction> {
+        XsTransaction::transaction(self)
+    }
+}
+
+impl AsyncXs for XsMemoryTransaction {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        Xs::directory(self, path)
+    }
+
+    async fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        Xs::read_bytes(self, path)
+    }
+
+    async fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        Xs::write_bytes(self, path, data)
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        Xs::rm(self, path)
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        Xs::mkdir(self, path)
+    }
+
+    async fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        Xs::get_perms(self, path)
+    }
+
+    async fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        Xs::set_perms(self, path, perms)
+    }
+
+    async fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        Xs::get_domain_path(self, domid)
+    }
+}
+
+impl AsyncXsTransactionSpan for XsMemoryTransaction {
+    async fn commit(self) -> io::Result<()> {
+        XsTransactionSpan::commit(self)
+    }
+}
+
+impl AsyncWatch for XsMemory {
+    async fn watch(
+        &self,
+        path: &str,
+    ) -> io::Result<impl futures::Stream<Item = Box<str>> + Unpin + 'static> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let handle = self.watch_with(path, WatchSender::Async(sender))?;
+
+        Ok(XsMemoryAsyncWatch {
+            _handle: handle,
+            receiver,
+        })
+    }
+}
+
+/// In-memory async watch object.
+struct XsMemoryAsyncWatch {
+    _handle: XsMemoryWatchHandle,
+    receiver: futures::channel::mpsc::UnboundedReceiver<Box<str>>,
+}
+
+impl futures::Stream for XsMemoryAsyncWatch {
+    type Item = Box<str>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        std::pin::Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use super::XsMemory;
+    use crate::{
+        Xs, XsAccess, XsDomainControl, XsErrno, XsError, XsPermission, XsTransaction,
+        XsTransactionSpan, XsWatch,
+    };
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_millis(100));
+
+    fn errno(error: &io::Error) -> Option<XsErrno> {
+        XsError::from_io(error)?.errno().cloned()
+    }
+
+    #[test]
+    fn permissions() {
+        let xs = XsMemory::new();
+        let guest = xs.store().connect(1);
+
+        xs.write("/private", "secret").unwrap();
+        let error = guest.read("/private").unwrap_err();
+        assert_eq!(errno(&error), Some(XsErrno::EACCES));
+        let error = guest.write("/private", "changed").unwrap_err();
+        assert_eq!(errno(&error), Some(XsErrno::EACCES));
+        let error = guest.mkdir("/private").unwrap_err();
+        assert_eq!(errno(&error), Some(XsErrno::EACCES));
+
+        // Readable by everyone, writable by its owner only.
+        xs.set_perms(
+            "/private",
+            &[XsPermission {
+                domid: 0,
+                access: XsAccess::Read,
+            }],
+        )
+        .unwrap();
+        assert_eq!(&*guest.read("/private").unwrap(), "secret");
+        let error = guest.write("/private", "changed").unwrap_err();
+        assert_eq!(errno(&error), Some(XsErrno::EACCES));
+
+        // Only the owner can change permissions.
+        let error = guest
+            .set_perms(
+                "/private",
+                &[XsPermission {
+                    domid: 1,
+                    access: XsAccess::Both,
+                }],
+            )
+            .unwrap_err();
+        assert_eq!(errno(&error), Some(XsErrno::EACCES));
+
+        // Domain control is privileged.
+        let error = guest.introduce(2, 0, 0).unwrap_err();
+        assert_eq!(errno(&error), Some(XsErrno::EACCES));
+    }
+
+    #[test]
+    fn transaction_conflict() {
+        let xs = XsMemory::new();
+        xs.write("/a", "1").unwrap();
+
+        let transaction = xs.transaction().unwrap();
+        assert_eq!(&*transaction.read("/a").unwrap(), "1");
+        transaction.write("/b", "2").unwrap();
+
+        // Node accessed by the transaction modified meanwhile.
+        xs.write("/a", "3").unwrap();
+
+        let error = transaction.commit().unwrap_err();
+        assert_eq!(errno(&error), Some(XsErrno::EAGAIN));
+        assert_eq!(errno(&xs.read("/b").unwrap_err()), Some(XsErrno::ENOENT));
+    }
+
+    #[test]
+    fn transaction_commit() {
+        let xs = XsMemory::new();
+        xs.write("/a", "1").unwrap();
+
+        let transaction = xs.transaction().unwrap();
+        transaction.write("/b", "2").unwrap();
+
+        // Unrelated modification (not creating a node, which would modify `/`).
+        xs.write("/a", "3").unwrap();
+        assert_eq!(errno(&xs.read("/b").unwrap_err()), Some(XsErrno::ENOENT));
+
+        transaction.commit().unwrap();
+        assert_eq!(&*xs.read("/b").unwrap(), "2");
+    }
+
+    #[test]
+    fn watch_recursive() {
+        let xs = XsMemory::new();
+        xs.write("/a/b", "").unwrap();
+
+        let mut watch = xs.watch("/a").unwrap();
+        let mut sub_watch = xs.watch("/a/b/c").unwrap();
+
+        // Watches fire once registered.
+        assert_eq!(watch.next_timeout(TIMEOUT).unwrap().as_deref(), Some("/a"));
+        assert_eq!(
+            sub_watch.next_timeout(TIMEOUT).unwrap().as_deref(),
+            Some("/a/b/c")
+        );
+
+        // Modifications of subnodes.
+        xs.write("/a/b/c", "1").unwrap();
+        assert_eq!(
+            watch.next_timeout(TIMEOUT).unwrap().as_deref(),
+            Some("/a/b/c")
+        );
+        assert_eq!(
+            sub_watch.next_timeout(TIMEOUT).unwrap().as_deref(),
+            Some("/a/b/c")
+        );
+
+        // Unrelated modification.
+        xs.write("/d", "1").unwrap();
+        assert_eq!(watch.next_timeout(TIMEOUT).unwrap(), None);
+
+        // Removing an ancestor fires the watches of its subnodes.
+        xs.rm("/a").unwrap();
+        assert_eq!(watch.next_timeout(TIMEOUT).unwrap().as_deref(), Some("/a"));
+        assert_eq!(
+            sub_watch.next_timeout(TIMEOUT).unwrap().as_deref(),
+            Some("/a/b/c")
+        );
+    }
+
+    #[test]
+    fn watch_reset() {
+        let xs = XsMemory::new();
+
+        let mut watch = xs.watch("/reset").unwrap();
+        assert_eq!(
+            watch.next_timeout(TIMEOUT).unwrap().as_deref(),
+            Some("/reset")
+        );
+
+        xs.reset_watches().unwrap();
+        assert!(watch.next_timeout(None).is_err());
+    }
+
+    #[test]
+    fn watch_domains() {
+        let xs = XsMemory::new();
+
+        let mut introduce = xs.watch("@introduceDomain").unwrap();
+        let mut release = xs.watch("@releaseDomain").unwrap();
+        assert!(introduce.next_timeout(TIMEOUT).unwrap().is_some());
+        assert!(release.next_timeout(TIMEOUT).unwrap().is_some());
+
+        // Node modifications don't fire special watches.
+        xs.write("/a", "1").unwrap();
+        assert_eq!(introduce.next_timeout(TIMEOUT).unwrap(), None);
+
+        xs.introduce(1, 0, 0).unwrap();
+        assert_eq!(
+            introduce.next_timeout(TIMEOUT).unwrap().as_deref(),
+            Some("@introduceDomain")
+        );
+        assert_eq!(release.next_timeout(TIMEOUT).unwrap(), None);
+
+        xs.release(1).unwrap();
+        assert_eq!(
+            release.next_timeout(TIMEOUT).unwrap().as_deref(),
+            Some("@releaseDomain")
+        );
+        assert_eq!(introduce.next_timeout(TIMEOUT).unwrap(), None);
+    }
+
+    #[test]
+    fn directory_large() {
+        let xs = XsMemory::new();
+        let names: Vec<String> = (0..100).map(|i| format!("{i:0>60}")).collect();
+
+        for name in &names {
+            xs.write(&format!("/dir/{name}"), "").unwrap();
+        }
+
+        // Falls back to DIRECTORY_PART.
+        let children = xs.directory("/dir").unwrap();
+        assert_eq!(children.len(), names.len());
+        assert!(children.iter().zip(&names).all(|(a, b)| **a == **b));
+    }
+}
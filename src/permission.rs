@@ -0,0 +1,75 @@
+//! Xenstore node permissions.
+//!
+//! A node has a list of permissions, the first one defines the owner of the node
+//! (who has full access) and the access of all domains not listed afterwards.
+//! Following permissions grant a specific access to a domain.
+
+use std::{fmt, str::FromStr};
+
+/// Access granted by a [XsPermission].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum XsAccess {
+    /// No access (`n`).
+    None,
+    /// Read only access (`r`).
+    Read,
+    /// Write only access (`w`).
+    Write,
+    /// Read and write access (`b`).
+    Both,
+}
+
+impl XsAccess {
+    /// Check if this access allows reading.
+    pub fn can_read(self) -> bool {
+        matches!(self, XsAccess::Read | XsAccess::Both)
+    }
+
+    /// Check if this access allows writing.
+    pub fn can_write(self) -> bool {
+        matches!(self, XsAccess::Write | XsAccess::Both)
+    }
+}
+
+/// Permission of a domain over a node (e.g `r5` means domain 5 can read).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct XsPermission {
+    pub domid: u16,
+    pub access: XsAccess,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct InvalidPermission;
+
+impl FromStr for XsPermission {
+    type Err = InvalidPermission;
+
+    fn from_str(s: &str) -> Result<Self, InvalidPermission> {
+        let mut chars = s.chars();
+
+        let access = match chars.next() {
+            Some('n') => XsAccess::None,
+            Some('r') => XsAccess::Read,
+            Some('w') => XsAccess::Write,
+            Some('b') => XsAccess::Both,
+            _ => return Err(InvalidPermission),
+        };
+
+        let domid = chars.as_str().parse().map_err(|_| InvalidPermission)?;
+
+        Ok(Self { domid, access })
+    }
+}
+
+impl fmt::Display for XsPermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            XsAccess::None => 'n',
+            XsAccess::Read => 'r',
+            XsAccess::Write => 'w',
+            XsAccess::Both => 'b',
+        };
+
+        write!(f, "{access}{}", self.domid)
+    }
+}
@@ -0,0 +1,642 @@
+//! Xenstore wire protocol.
+//!
+//! Message framing ([XsMessageHeader], [XsMessage]) and typed payloads
+//! ([XsRequest], [XsResponse]) as used by the clients and the server of this crate,
+//! for building other xenstore tools (e.g proxies, recorders or servers).
+
+use std::{
+    convert::TryInto,
+    io::{self, ErrorKind, Read, Write},
+    str::{self, FromStr, Utf8Error},
+};
+
+use crate::{XsErrno, XsError, XsPermission, XsProtocolError};
+
+mod request;
+
+pub use request::{XsRequest, XsResponse};
+
+// TODO: Replace with cfg_match! when available.
+//       https://github.com/rust-lang/rust/pull/115416
+/// xenbus device path
+#[cfg(not(target_os = "windows"))]
+pub const XENBUS_DEVICE_PATH: &str = if cfg!(target_os = "freebsd") {
+    "/dev/xen/xenstore"
+} else if cfg!(target_os = "netbsd") {
+    "/kern/xen/xenbus"
+} else {
+    "/dev/xen/xenbus"
+};
+
+/// Maximum payload length of a message.
+pub const XENSTORE_PAYLOAD_MAX: usize = 4096;
+
+/// Xenstore supports reconnecting a domain ring (see [XsMessageType::GetFeature]).
+pub const XENSTORE_SERVER_FEATURE_RECONNECTION: u32 = 1;
+/// Xenstore can report ring errors.
+pub const XENSTORE_SERVER_FEATURE_ERROR: u32 = 2;
+
+/// Xenstore message type.
+///
+/// Compared by their wire value, so that an [XsMessageType::Unknown] holding the
+/// value of a known type is equal to it.
+#[derive(Clone, Copy, Debug, Eq)]
+#[non_exhaustive]
+pub enum XsMessageType {
+    Control,
+    Directory,
+    Read,
+    GetPerms,
+    Watch,
+    Unwatch,
+    TransactionStart,
+    TransactionEnd,
+    Introduce,
+    Release,
+    GetDomainPath,
+    Write,
+    Mkdir,
+    Rm,
+    SetPerms,
+    WatchEvent,
+    Error,
+    IsDomainIntroduced,
+    Resume,
+    SetTarget,
+    ResetWatches,
+    DirectoryPart,
+    GetFeature,
+    SetFeature,
+    GetQuota,
+    SetQuota,
+    /// Message type unknown to this crate (e.g introduced by a newer Xen).
+    ///
+    /// Only built by [`From<u32>`] with a value that is none of the types above,
+    /// prefer it over building this variant directly.
+    Unknown(u32),
+}
+
+impl PartialEq for XsMessageType {
+    fn eq(&self, other: &Self) -> bool {
+        u32::from(*self) == u32::from(*other)
+    }
+}
+
+impl From<XsMessageType> for u32 {
+    fn from(val: XsMessageType) -> Self {
+        match val {
+            XsMessageType::Control => 0,
+            XsMessageType::Directory => 1,
+            XsMessageType::Read => 2,
+            XsMessageType::GetPerms => 3,
+            XsMessageType::Watch => 4,
+            XsMessageType::Unwatch => 5,
+            XsMessageType::TransactionStart => 6,
+            XsMessageType::TransactionEnd => 7,
+            XsMessageType::Introduce => 8,
+            XsMessageType::Release => 9,
+            XsMessageType::GetDomainPath => 10,
+            XsMessageType::Write => 11,
+            XsMessageType::Mkdir => 12,
+            XsMessageType::Rm => 13,
+            XsMessageType::SetPerms => 14,
+            XsMessageType::WatchEvent => 15,
+            XsMessageType::Error => 16,
+            XsMessageType::IsDomainIntroduced => 17,
+            XsMessageType::Resume => 18,
+            XsMessageType::SetTarget => 19,
+            XsMessageType::ResetWatches => 21,
+            XsMessageType::DirectoryPart => 22,
+            XsMessageType::GetFeature => 23,
+            XsMessageType::SetFeature => 24,
+            XsMessageType::GetQuota => 25,
+            XsMessageType::SetQuota => 26,
+            XsMessageType::Unknown(value) => value,
+        }
+    }
+}
+
+impl From<u32> for XsMessageType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Control,
+            1 => Self::Directory,
+            2 => Self::Read,
+            3 => Self::GetPerms,
+            4 => Self::Watch,
+            5 => Self::Unwatch,
+            6 => Self::TransactionStart,
+            7 => Self::TransactionEnd,
+            8 => Self::Introduce,
+            9 => Self::Release,
+            10 => Self::GetDomainPath,
+            11 => Self::Write,
+            12 => Self::Mkdir,
+            13 => Self::Rm,
+            14 => Self::SetPerms,
+            15 => Self::WatchEvent,
+            16 => Self::Error,
+            17 => Self::IsDomainIntroduced,
+            18 => Self::Resume,
+            19 => Self::SetTarget,
+            21 => Self::ResetWatches,
+            22 => Self::DirectoryPart,
+            23 => Self::GetFeature,
+            24 => Self::SetFeature,
+            25 => Self::GetQuota,
+            26 => Self::SetQuota,
+            value => Self::Unknown(value),
+        }
+    }
+}
+
+/// Message header.
+///
+/// ```c
+/// struct xsd_sockmsg
+/// {
+///     uint32_t type;  /* XS_??? */
+///     uint32_t req_id;/* Request identifier, echoed in daemon's response.  */
+///     uint32_t tx_id; /* Transaction id (0 if not related to a transaction). */
+///     uint32_t len;   /* Length of data following this. */
+///
+///     /* Generally followed by nul-terminated string(s). */
+/// };
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XsMessageHeader {
+    /// Raw message type (see [XsMessageType]).
+    pub msg_type: u32,
+    /// Request identifier, echoed in the response.
+    pub request_id: u32,
+    /// Transaction id (0 if not related to a transaction).
+    pub tx_id: u32,
+    /// Length of the payload following the header.
+    pub len: u32,
+}
+
+impl XsMessageHeader {
+    /// Size of the header (4 * u32).
+    pub const SIZE: usize = 16;
+
+    /// Decode a header (native endian).
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let field = |index: usize| {
+            u32::from_ne_bytes(bytes[4 * index..4 * (index + 1)].try_into().unwrap())
+        };
+
+        Self {
+            msg_type: field(0),
+            request_id: field(1),
+            tx_id: field(2),
+            len: field(3),
+        }
+    }
+
+    /// Encode the header (native endian).
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        let fields = [self.msg_type, self.request_id, self.tx_id, self.len];
+
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_ne_bytes());
+        }
+
+        bytes
+    }
+
+    /// Get the message type.
+    pub fn message_type(&self) -> XsMessageType {
+        XsMessageType::from(self.msg_type)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct XsMessage {
+    pub msg_type: XsMessageType,
+    pub request_id: u32,
+    /// Transaction id (0 if not related to a transaction).
+    pub tx_id: u32,
+    pub payload: Box<[u8]>,
+}
+
+fn parse_nul_string(mut buffer: &[u8]) -> Result<Option<&str>, Utf8Error> {
+    // Assuming terminating NUL
+    if buffer.is_empty() {
+        Ok(None)
+    } else {
+        // Discard latest NUL character (if present)
+        if buffer.last() == Some(&0) {
+            buffer = &buffer[..buffer.len() - 1];
+        }
+
+        Some(str::from_utf8(buffer)).transpose()
+    }
+}
+
+/// Check the payload length of a message, either outgoing or incoming
+/// (before allocating it).
+pub fn check_payload_len(len: usize) -> io::Result<()> {
+    if len > XENSTORE_PAYLOAD_MAX {
+        return Err(XsProtocolError::PayloadTooLarge(len).into());
+    }
+
+    Ok(())
+}
+
+/// Parse a list of NUL terminated strings.
+pub fn parse_nul_list(buffer: &[u8]) -> Result<Vec<&str>, Utf8Error> {
+    buffer
+        .split_inclusive(|&c| c == 0)
+        .filter_map(|s| parse_nul_string(s).transpose())
+        .collect()
+}
+
+impl XsMessage {
+    /// Build a message from a list of strings, each NUL terminated
+    /// (but the last if `trailing_nul` is not set).
+    pub fn from_string_slice(
+        msg_type: XsMessageType,
+        request_id: u32,
+        strings: &[&'_ str],
+        trailing_nul: bool,
+    ) -> Self {
+        let bytes: Vec<&[u8]> = strings.iter().map(|s| s.as_bytes()).collect();
+
+        Self::from_bytes_slice(msg_type, request_id, &bytes, trailing_nul)
+    }
+
+    /// Alike [XsMessage::from_string_slice] but with arbitrary bytes.
+    pub fn from_bytes_slice(
+        msg_type: XsMessageType,
+        request_id: u32,
+        slices: &[&'_ [u8]],
+        trailing_nul: bool,
+    ) -> Self {
+        let mut payload: Vec<u8> = Vec::new();
+
+        for s in slices {
+            payload.write_all(s).unwrap(); // infailble
+            payload.push(0);
+        }
+
+        // Remove trailing NUL character (e.g WRITE)
+        if !trailing_nul {
+            payload.remove(payload.len() - 1);
+        }
+
+        Self {
+            msg_type,
+            request_id,
+            tx_id: 0,
+            payload: payload.into_boxed_slice(),
+        }
+    }
+
+    pub fn write_to(&self, writer: &'_ mut impl Write) -> io::Result<()> {
+        check_payload_len(self.payload.len())?;
+
+        let header = self.header().to_bytes();
+
+        // TODO: Use write_all_vectored when available.
+        //       https://github.com/rust-lang/rust/issues/70436
+        writer.write_all(&header)?;
+        writer.write_all(&self.payload)?;
+
+        Ok(())
+    }
+
+    /// Get the header of this message.
+    pub fn header(&self) -> XsMessageHeader {
+        XsMessageHeader {
+            msg_type: u32::from(self.msg_type),
+            request_id: self.request_id,
+            tx_id: self.tx_id,
+            len: self.payload.len() as u32,
+        }
+    }
+
+    /// Build a message from its header and payload.
+    pub fn from_parts(header: XsMessageHeader, payload: Box<[u8]>) -> Self {
+        XsMessage {
+            msg_type: header.message_type(),
+            request_id: header.request_id,
+            tx_id: header.tx_id,
+            payload,
+        }
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut raw_header = [0u8; XsMessageHeader::SIZE];
+        reader.read_exact(&mut raw_header)?;
+
+        let header = XsMessageHeader::from_bytes(&raw_header);
+        let len = header.len as usize;
+
+        check_payload_len(len)?;
+        let mut payload = vec![0u8; len];
+
+        reader.read_exact(&mut payload)?;
+
+        Ok(Self::from_parts(header, payload.into_boxed_slice()))
+    }
+
+    pub fn parse_payload_str(&self) -> Result<Option<&str>, Utf8Error> {
+        parse_nul_string(&self.payload)
+    }
+
+    /// Parse a payload made of a single integer (e.g transaction id).
+    pub fn parse_payload_int<T: FromStr>(&self) -> io::Result<T> {
+        self.parse_payload_str()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Got invalid integer payload"))
+    }
+
+    /// Parse a boolean (`T` or `F`) payload.
+    pub fn parse_payload_bool(&self) -> io::Result<bool> {
+        match self.parse_payload_str() {
+            Ok(Some("T")) => Ok(true),
+            Ok(Some("F")) => Ok(false),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Got invalid boolean payload",
+            )),
+        }
+    }
+
+    pub fn parse_payload_list(&self) -> Result<Vec<&str>, Utf8Error> {
+        parse_nul_list(&self.payload)
+    }
+
+    /// Parse a DIRECTORY_PART response into the generation count of the node
+    /// and the raw chunk of the children list.
+    pub fn parse_directory_part(&self) -> io::Result<(&str, &[u8])> {
+        let Some(gen_len) = self.payload.iter().position(|&c| c == 0) else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid directory part payload received",
+            ));
+        };
+
+        let generation = str::from_utf8(&self.payload[..gen_len])
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok((generation, &self.payload[gen_len + 1..]))
+    }
+
+    /// Get the error code (e.g `ENOENT`) of an error message.
+    pub fn errno(&self) -> Option<XsErrno> {
+        if self.msg_type != XsMessageType::Error {
+            return None;
+        }
+
+        let Ok(Some(code)) = self.parse_payload_str() else {
+            return None;
+        };
+
+        code.parse().ok()
+    }
+
+    /// Check that this message is a response to a `request_type` request.
+    /// Error messages are converted to [io::Error] (see [XsMessage::parse_error]).
+    pub fn check_response(
+        self,
+        request_type: XsMessageType,
+        path: Option<&str>,
+    ) -> io::Result<Self> {
+        match self.msg_type {
+            // Response type must match request.
+            msg_type if msg_type == request_type => Ok(self),
+            XsMessageType::Error => Err(self.parse_error(request_type, path)),
+            msg_type => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Got unrelated response ({msg_type:?})"),
+            )),
+        }
+    }
+
+    pub fn parse_payload_perms(&self) -> io::Result<Vec<XsPermission>> {
+        self.parse_payload_list()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+            .iter()
+            .map(|s| {
+                s.parse()
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Got invalid permission"))
+            })
+            .collect()
+    }
+
+    /// Parse an error message into a [XsError] (wrapped in [io::Error]),
+    /// `request_type` and `path` describe the failed request.
+    pub fn parse_error(&self, request_type: XsMessageType, path: Option<&str>) -> io::Error {
+        let Some(errno) = self.errno() else {
+            return io::Error::other("Got invalid error code from error payload");
+        };
+
+        XsError::Xenstore {
+            errno,
+            request: request_type,
+            path: path.map(Box::from),
+        }
+        .into()
+    }
+}
+
+/// Number of times a DIRECTORY_PART listing is restarted because of concurrent
+/// modifications before giving up with `EAGAIN`.
+const DIRECTORY_PART_MAX_RESTARTS: u32 = 16;
+
+/// State of a directory listing made of DIRECTORY_PART requests.
+///
+/// Request the chunk at [XsDirectoryPart::offset] and give each response to
+/// [XsDirectoryPart::push], until it yields the complete listing.
+/// If the directory is modified meanwhile (generation change), the listing
+/// starts over.
+#[derive(Clone, Debug)]
+pub struct XsDirectoryPart {
+    path: Box<str>,
+    generation: Option<u64>,
+    children: Vec<u8>,
+    restarts: u32,
+}
+
+impl XsDirectoryPart {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.into(),
+            generation: None,
+            children: Vec::new(),
+            restarts: 0,
+        }
+    }
+
+    /// Offset of the next chunk to request.
+    pub fn offset(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Next request to make.
+    pub fn request(&self) -> XsRequest {
+        XsRequest::DirectoryPart {
+            path: self.path.clone(),
+            offset: self.offset(),
+        }
+    }
+
+    /// Add a chunk of the listing, yields the children once the listing is complete.
+    ///
+    /// Fails with `EAGAIN` if the directory keeps being modified.
+    pub fn push(&mut self, generation: u64, chunk: &[u8]) -> io::Result<Option<Vec<Box<str>>>> {
+        match self.generation {
+            // Directory has been modified meanwhile, start over.
+            Some(current) if current != generation => {
+                self.restarts += 1;
+
+                if self.restarts > DIRECTORY_PART_MAX_RESTARTS {
+                    return Err(XsError::Xenstore {
+                        errno: XsErrno::EAGAIN,
+                        request: XsMessageType::DirectoryPart,
+                        path: Some(self.path.clone()),
+                    }
+                    .into());
+                }
+
+                self.generation = None;
+                self.children.clear();
+                return Ok(None);
+            }
+            Some(_) => {}
+            None => self.generation = Some(generation),
+        }
+
+        self.children.extend_from_slice(chunk);
+
+        // The end of the list is marked by an empty name.
+        if self.children.len() > 1 && !self.children.ends_with(&[0, 0]) {
+            return Ok(None);
+        }
+
+        self.children.pop();
+
+        Ok(Some(
+            parse_nul_list(&self.children)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+                // convert &str to Box<str>
+                .iter()
+                .map(|s| s.to_string().into_boxed_str())
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_type_round_trip() {
+        for value in 0..64 {
+            let msg_type = XsMessageType::from(value);
+            assert_eq!(u32::from(msg_type), value);
+            assert_eq!(XsMessageType::from(u32::from(msg_type)), msg_type);
+        }
+
+        assert_eq!(XsMessageType::from(20), XsMessageType::Unknown(20));
+        assert_ne!(XsMessageType::Unknown(20), XsMessageType::Unknown(27));
+        // Unknown holding a known value.
+        assert_eq!(XsMessageType::Unknown(2), XsMessageType::Read);
+        assert_ne!(XsMessageType::Unknown(2), XsMessageType::Directory);
+    }
+
+    #[test]
+    fn write_oversized() {
+        let message = XsMessage::from_bytes_slice(
+            XsMessageType::Write,
+            1,
+            &[b"/a", &[0; XENSTORE_PAYLOAD_MAX]],
+            false,
+        );
+        let mut buffer = Vec::new();
+
+        let error = message.write_to(&mut buffer).unwrap_err();
+        assert!(matches!(
+            XsError::from_io(&error),
+            Some(XsError::Protocol(XsProtocolError::PayloadTooLarge(_)))
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn directory_part_single_chunk() {
+        let mut listing = XsDirectoryPart::new("/a");
+
+        let children = listing.push(1, b"b\0c\0\0").unwrap();
+        assert_eq!(children, Some(vec!["b".into(), "c".into()]));
+    }
+
+    #[test]
+    fn directory_part_empty() {
+        let mut listing = XsDirectoryPart::new("/a");
+
+        assert_eq!(listing.push(1, b"\0").unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn directory_part_chunks() {
+        let mut listing = XsDirectoryPart::new("/a");
+
+        assert_eq!(listing.push(3, b"b\0c").unwrap(), None);
+        assert_eq!(listing.offset(), 3);
+        assert_eq!(
+            listing.push(3, b"c\0d\0\0").unwrap(),
+            Some(vec!["b".into(), "cc".into(), "d".into()])
+        );
+    }
+
+    #[test]
+    fn directory_part_modified_meanwhile() {
+        let mut listing = XsDirectoryPart::new("/a");
+
+        assert_eq!(listing.push(1, b"b\0").unwrap(), None);
+        // Directory modified before the next chunk: start over.
+        assert_eq!(listing.push(2, b"x\0\0").unwrap(), None);
+        assert_eq!(listing.offset(), 0);
+        assert!(matches!(
+            listing.request(),
+            XsRequest::DirectoryPart { offset: 0, .. }
+        ));
+
+        // The new generation is then accepted.
+        assert_eq!(listing.push(2, b"b\0").unwrap(), None);
+        assert_eq!(
+            listing.push(2, b"x\0\0").unwrap(),
+            Some(vec!["b".into(), "x".into()])
+        );
+    }
+
+    #[test]
+    fn directory_part_restarts_capped() {
+        let mut listing = XsDirectoryPart::new("/a");
+        let mut generation = 0;
+
+        let error = loop {
+            listing.push(generation, b"b\0").unwrap();
+            generation += 1;
+
+            if let Err(e) = listing.push(generation, b"c\0\0") {
+                break e;
+            }
+        };
+
+        assert_eq!(generation, u64::from(DIRECTORY_PART_MAX_RESTARTS) + 1);
+        assert!(matches!(
+            XsError::from_io(&error),
+            Some(XsError::Xenstore {
+                errno: XsErrno::EAGAIN,
+                request: XsMessageType::DirectoryPart,
+                ..
+            })
+        ));
+    }
+}
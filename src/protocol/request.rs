@@ -0,0 +1,851 @@
+//! Typed xenstore requests and responses.
+//!
+//! Own the payload encoding rules of each message type (e.g trailing NUL),
+//! on top of [XsMessage].
+
+use std::{
+    io::{self, ErrorKind},
+    str::{self, FromStr},
+};
+
+use super::{XsMessage, XsMessageType};
+use crate::{control::LIVE_UPDATE_DATA, XsErrno, XsPermission};
+
+/// Xenstore request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum XsRequest {
+    Control {
+        args: Vec<Box<str>>,
+        /// Raw data following the arguments (e.g `live-update -d` chunk).
+        data: Option<Box<[u8]>>,
+    },
+    Directory {
+        path: Box<str>,
+    },
+    Read {
+        path: Box<str>,
+    },
+    GetPerms {
+        path: Box<str>,
+    },
+    Watch {
+        path: Box<str>,
+        token: Box<str>,
+    },
+    Unwatch {
+        path: Box<str>,
+        token: Box<str>,
+    },
+    TransactionStart,
+    TransactionEnd {
+        commit: bool,
+    },
+    Introduce {
+        domid: u16,
+        mfn: u64,
+        evtchn: u32,
+    },
+    Release {
+        domid: u16,
+    },
+    GetDomainPath {
+        domid: u16,
+    },
+    Write {
+        path: Box<str>,
+        value: Box<[u8]>,
+    },
+    Mkdir {
+        path: Box<str>,
+    },
+    Rm {
+        path: Box<str>,
+    },
+    SetPerms {
+        path: Box<str>,
+        perms: Vec<XsPermission>,
+    },
+    IsDomainIntroduced {
+        domid: u16,
+    },
+    Resume {
+        domid: u16,
+    },
+    SetTarget {
+        domid: u16,
+        target: u16,
+    },
+    ResetWatches,
+    DirectoryPart {
+        path: Box<str>,
+        offset: usize,
+    },
+    /// Get the features of xenstored, or of a domain.
+    GetFeature {
+        domid: Option<u16>,
+    },
+    SetFeature {
+        domid: u16,
+        features: u32,
+    },
+    /// Get the names of the quotas (without `quota`) or the value of a quota,
+    /// either global or of a domain (`domid` is ignored without `quota`).
+    GetQuota {
+        domid: Option<u16>,
+        quota: Option<Box<str>>,
+    },
+    SetQuota {
+        domid: Option<u16>,
+        quota: Box<str>,
+        value: u32,
+    },
+}
+
+fn unknown_type(value: u32) -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!("Got unknown message type ({value})"),
+    )
+}
+
+fn invalid_payload(msg_type: XsMessageType) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid {msg_type:?} payload"),
+    )
+}
+
+fn parse_arg<T: FromStr>(msg_type: XsMessageType, arg: &str) -> io::Result<T> {
+    arg.parse().map_err(|_| invalid_payload(msg_type))
+}
+
+impl XsRequest {
+    pub fn msg_type(&self) -> XsMessageType {
+        match self {
+            XsRequest::Control { .. } => XsMessageType::Control,
+            XsRequest::Directory { .. } => XsMessageType::Directory,
+            XsRequest::Read { .. } => XsMessageType::Read,
+            XsRequest::GetPerms { .. } => XsMessageType::GetPerms,
+            XsRequest::Watch { .. } => XsMessageType::Watch,
+            XsRequest::Unwatch { .. } => XsMessageType::Unwatch,
+            XsRequest::TransactionStart => XsMessageType::TransactionStart,
+            XsRequest::TransactionEnd { .. } => XsMessageType::TransactionEnd,
+            XsRequest::Introduce { .. } => XsMessageType::Introduce,
+            XsRequest::Release { .. } => XsMessageType::Release,
+            XsRequest::GetDomainPath { .. } => XsMessageType::GetDomainPath,
+            XsRequest::Write { .. } => XsMessageType::Write,
+            XsRequest::Mkdir { .. } => XsMessageType::Mkdir,
+            XsRequest::Rm { .. } => XsMessageType::Rm,
+            XsRequest::SetPerms { .. } => XsMessageType::SetPerms,
+            XsRequest::IsDomainIntroduced { .. } => XsMessageType::IsDomainIntroduced,
+            XsRequest::Resume { .. } => XsMessageType::Resume,
+            XsRequest::SetTarget { .. } => XsMessageType::SetTarget,
+            XsRequest::ResetWatches => XsMessageType::ResetWatches,
+            XsRequest::DirectoryPart { .. } => XsMessageType::DirectoryPart,
+            XsRequest::GetFeature { .. } => XsMessageType::GetFeature,
+            XsRequest::SetFeature { .. } => XsMessageType::SetFeature,
+            XsRequest::GetQuota { .. } => XsMessageType::GetQuota,
+            XsRequest::SetQuota { .. } => XsMessageType::SetQuota,
+        }
+    }
+
+    /// Get the node path of the request (if it has one).
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            XsRequest::Directory { path }
+            | XsRequest::Read { path }
+            | XsRequest::GetPerms { path }
+            | XsRequest::Watch { path, .. }
+            | XsRequest::Unwatch { path, .. }
+            | XsRequest::Write { path, .. }
+            | XsRequest::Mkdir { path }
+            | XsRequest::Rm { path }
+            | XsRequest::SetPerms { path, .. }
+            | XsRequest::DirectoryPart { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Encode the request into a message.
+    pub fn encode(&self, request_id: u32, tx_id: u32) -> XsMessage {
+        let msg_type = self.msg_type();
+        let strings =
+            |strings: &[&str]| XsMessage::from_string_slice(msg_type, request_id, strings, true);
+
+        let message = match self {
+            XsRequest::Control { args, data: None } => {
+                strings(&args.iter().map(|arg| &**arg).collect::<Vec<_>>())
+            }
+            // Data is not NUL terminated.
+            XsRequest::Control {
+                args,
+                data: Some(data),
+            } => {
+                let mut slices: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+                slices.push(data);
+                XsMessage::from_bytes_slice(msg_type, request_id, &slices, false)
+            }
+            XsRequest::Directory { path }
+            | XsRequest::Read { path }
+            | XsRequest::GetPerms { path }
+            | XsRequest::Mkdir { path }
+            | XsRequest::Rm { path } => strings(&[path]),
+            XsRequest::Watch { path, token } | XsRequest::Unwatch { path, token } => {
+                strings(&[path, token])
+            }
+            XsRequest::TransactionStart | XsRequest::ResetWatches => strings(&[""]),
+            XsRequest::TransactionEnd { commit } => strings(&[if *commit { "T" } else { "F" }]),
+            XsRequest::Introduce { domid, mfn, evtchn } => {
+                strings(&[&domid.to_string(), &mfn.to_string(), &evtchn.to_string()])
+            }
+            XsRequest::Release { domid }
+            | XsRequest::GetDomainPath { domid }
+            | XsRequest::IsDomainIntroduced { domid }
+            | XsRequest::Resume { domid } => strings(&[&domid.to_string()]),
+            // Value is not NUL terminated.
+            XsRequest::Write { path, value } => {
+                XsMessage::from_bytes_slice(msg_type, request_id, &[path.as_bytes(), value], false)
+            }
+            XsRequest::SetPerms { path, perms } => {
+                let perms: Vec<String> = perms.iter().map(XsPermission::to_string).collect();
+                let mut args = vec![&**path];
+                args.extend(perms.iter().map(String::as_str));
+
+                strings(&args)
+            }
+            XsRequest::SetTarget { domid, target } => {
+                strings(&[&domid.to_string(), &target.to_string()])
+            }
+            XsRequest::DirectoryPart { path, offset } => strings(&[path, &offset.to_string()]),
+            // Optional arguments are omitted, not empty.
+            XsRequest::GetFeature { domid: None } => strings(&[]),
+            XsRequest::GetFeature { domid: Some(domid) } => strings(&[&domid.to_string()]),
+            XsRequest::SetFeature { domid, features } => {
+                strings(&[&domid.to_string(), &features.to_string()])
+            }
+            XsRequest::GetQuota { quota: None, .. } => strings(&[]),
+            XsRequest::GetQuota {
+                domid: None,
+                quota: Some(quota),
+            } => strings(&[quota]),
+            XsRequest::GetQuota {
+                domid: Some(domid),
+                quota: Some(quota),
+            } => strings(&[&domid.to_string(), quota]),
+            XsRequest::SetQuota {
+                domid: None,
+                quota,
+                value,
+            } => strings(&[quota, &value.to_string()]),
+            XsRequest::SetQuota {
+                domid: Some(domid),
+                quota,
+                value,
+            } => strings(&[&domid.to_string(), quota, &value.to_string()]),
+        };
+
+        XsMessage { tx_id, ..message }
+    }
+
+    /// Decode a request message.
+    pub fn decode(message: &XsMessage) -> io::Result<Self> {
+        let msg_type = message.msg_type;
+
+        if let XsMessageType::Unknown(value) = msg_type {
+            return Err(unknown_type(value));
+        }
+
+        // WRITE value is the raw payload after the path.
+        if msg_type == XsMessageType::Write {
+            let index = message
+                .payload
+                .iter()
+                .position(|&c| c == 0)
+                .ok_or_else(|| invalid_payload(msg_type))?;
+
+            return Ok(XsRequest::Write {
+                path: str::from_utf8(&message.payload[..index])
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+                    .into(),
+                value: message.payload[index + 1..].into(),
+            });
+        }
+
+        // Live update data is the raw payload after the arguments.
+        if msg_type == XsMessageType::Control {
+            if let Some(data) = message.payload.strip_prefix(LIVE_UPDATE_DATA) {
+                return Ok(XsRequest::Control {
+                    args: vec!["live-update".into(), "-d".into()],
+                    data: Some(data.into()),
+                });
+            }
+        }
+
+        let args = message
+            .parse_payload_list()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let parse = |arg: &str| parse_arg(msg_type, arg);
+
+        Ok(match (msg_type, &args[..]) {
+            (XsMessageType::Control, args) => XsRequest::Control {
+                args: args.iter().map(|&arg| arg.into()).collect(),
+                data: None,
+            },
+            (XsMessageType::Directory, [path]) => XsRequest::Directory {
+                path: (*path).into(),
+            },
+            (XsMessageType::Read, [path]) => XsRequest::Read {
+                path: (*path).into(),
+            },
+            (XsMessageType::GetPerms, [path]) => XsRequest::GetPerms {
+                path: (*path).into(),
+            },
+            (XsMessageType::Watch, [path, token]) => XsRequest::Watch {
+                path: (*path).into(),
+                token: (*token).into(),
+            },
+            (XsMessageType::Unwatch, [path, token]) => XsRequest::Unwatch {
+                path: (*path).into(),
+                token: (*token).into(),
+            },
+            (XsMessageType::TransactionStart, _) => XsRequest::TransactionStart,
+            (XsMessageType::TransactionEnd, ["T"]) => XsRequest::TransactionEnd { commit: true },
+            (XsMessageType::TransactionEnd, ["F"]) => XsRequest::TransactionEnd { commit: false },
+            (XsMessageType::Introduce, [domid, mfn, evtchn]) => XsRequest::Introduce {
+                domid: parse_arg(msg_type, domid)?,
+                mfn: parse_arg(msg_type, mfn)?,
+                evtchn: parse_arg(msg_type, evtchn)?,
+            },
+            (XsMessageType::Release, [domid]) => XsRequest::Release {
+                domid: parse(domid)?,
+            },
+            (XsMessageType::GetDomainPath, [domid]) => XsRequest::GetDomainPath {
+                domid: parse(domid)?,
+            },
+            (XsMessageType::Mkdir, [path]) => XsRequest::Mkdir {
+                path: (*path).into(),
+            },
+            (XsMessageType::Rm, [path]) => XsRequest::Rm {
+                path: (*path).into(),
+            },
+            (XsMessageType::SetPerms, [path, perms @ ..]) => XsRequest::SetPerms {
+                path: (*path).into(),
+                perms: perms
+                    .iter()
+                    .map(|perm| parse_arg(msg_type, perm))
+                    .collect::<io::Result<_>>()?,
+            },
+            (XsMessageType::IsDomainIntroduced, [domid]) => XsRequest::IsDomainIntroduced {
+                domid: parse(domid)?,
+            },
+            (XsMessageType::Resume, [domid]) => XsRequest::Resume {
+                domid: parse(domid)?,
+            },
+            (XsMessageType::SetTarget, [domid, target]) => XsRequest::SetTarget {
+                domid: parse(domid)?,
+                target: parse(target)?,
+            },
+            (XsMessageType::ResetWatches, _) => XsRequest::ResetWatches,
+            (XsMessageType::DirectoryPart, [path, offset]) => XsRequest::DirectoryPart {
+                path: (*path).into(),
+                offset: parse_arg(msg_type, offset)?,
+            },
+            (XsMessageType::GetFeature, []) => XsRequest::GetFeature { domid: None },
+            (XsMessageType::GetFeature, [domid]) => XsRequest::GetFeature {
+                domid: Some(parse(domid)?),
+            },
+            (XsMessageType::SetFeature, [domid, features]) => XsRequest::SetFeature {
+                domid: parse(domid)?,
+                features: parse_arg(msg_type, features)?,
+            },
+            (XsMessageType::GetQuota, []) => XsRequest::GetQuota {
+                domid: None,
+                quota: None,
+            },
+            (XsMessageType::GetQuota, [quota]) => XsRequest::GetQuota {
+                domid: None,
+                quota: Some((*quota).into()),
+            },
+            (XsMessageType::GetQuota, [domid, quota]) => XsRequest::GetQuota {
+                domid: Some(parse(domid)?),
+                quota: Some((*quota).into()),
+            },
+            (XsMessageType::SetQuota, [quota, value]) => XsRequest::SetQuota {
+                domid: None,
+                quota: (*quota).into(),
+                value: parse_arg(msg_type, value)?,
+            },
+            (XsMessageType::SetQuota, [domid, quota, value]) => XsRequest::SetQuota {
+                domid: Some(parse(domid)?),
+                quota: (*quota).into(),
+                value: parse_arg(msg_type, value)?,
+            },
+            _ => return Err(invalid_payload(msg_type)),
+        })
+    }
+}
+
+/// Xenstore response (or watch event).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum XsResponse {
+    /// Acknowledgement of a request without result (`OK`).
+    Ok,
+    /// Raw output of a control command.
+    Control(Box<[u8]>),
+    Directory(Vec<Box<str>>),
+    Read(Box<[u8]>),
+    GetPerms(Vec<XsPermission>),
+    /// Id of the new transaction.
+    TransactionStart(u32),
+    GetDomainPath(Box<str>),
+    IsDomainIntroduced(bool),
+    /// Features bitmap (see [super::XENSTORE_SERVER_FEATURE_RECONNECTION]).
+    GetFeature(u32),
+    /// Quota value, or space separated quota names.
+    GetQuota(Box<str>),
+    /// Generation count of the node and raw chunk of its children list.
+    DirectoryPart {
+        generation: u64,
+        chunk: Box<[u8]>,
+    },
+    WatchEvent {
+        path: Box<str>,
+        token: Box<str>,
+    },
+    Error(XsErrno),
+}
+
+impl XsResponse {
+    /// Encode the response to a `request_type` request into a message.
+    pub fn encode(&self, request_type: XsMessageType, request_id: u32, tx_id: u32) -> XsMessage {
+        let strings = |msg_type, strings: &[&str]| {
+            XsMessage::from_string_slice(msg_type, request_id, strings, true)
+        };
+        let raw = |msg_type, payload: &[u8]| XsMessage {
+            msg_type,
+            request_id,
+            tx_id: 0,
+            payload: payload.into(),
+        };
+
+        let message = match self {
+            XsResponse::Ok => strings(request_type, &["OK"]),
+            XsResponse::Control(output) => raw(XsMessageType::Control, output),
+            XsResponse::Directory(children) => raw(
+                XsMessageType::Directory,
+                // Empty directory has an empty payload.
+                &children
+                    .iter()
+                    .flat_map(|name| name.bytes().chain([0]))
+                    .collect::<Vec<u8>>(),
+            ),
+            XsResponse::Read(value) => raw(XsMessageType::Read, value),
+            XsResponse::GetPerms(perms) => {
+                let perms: Vec<String> = perms.iter().map(XsPermission::to_string).collect();
+                let perms: Vec<&str> = perms.iter().map(String::as_str).collect();
+
+                strings(XsMessageType::GetPerms, &perms)
+            }
+            XsResponse::TransactionStart(tx_id) => {
+                strings(XsMessageType::TransactionStart, &[&tx_id.to_string()])
+            }
+            XsResponse::GetDomainPath(path) => strings(XsMessageType::GetDomainPath, &[path]),
+            XsResponse::IsDomainIntroduced(introduced) => strings(
+                XsMessageType::IsDomainIntroduced,
+                &[if *introduced { "T" } else { "F" }],
+            ),
+            XsResponse::GetFeature(features) => {
+                strings(XsMessageType::GetFeature, &[&features.to_string()])
+            }
+            XsResponse::GetQuota(quota) => strings(XsMessageType::GetQuota, &[quota]),
+            XsResponse::DirectoryPart { generation, chunk } => {
+                let mut payload = generation.to_string().into_bytes();
+                payload.push(0);
+                payload.extend_from_slice(chunk);
+
+                raw(XsMessageType::DirectoryPart, &payload)
+            }
+            // Watch events are unsolicited.
+            XsResponse::WatchEvent { path, token } => {
+                XsMessage::from_string_slice(XsMessageType::WatchEvent, 0, &[path, token], true)
+            }
+            XsResponse::Error(errno) => strings(XsMessageType::Error, &[errno.as_str()]),
+        };
+
+        XsMessage { tx_id, ..message }
+    }
+
+    /// Decode a response message (which is expected to be related to its request,
+    /// see [XsMessage::check_response]).
+    pub fn decode(message: &XsMessage) -> io::Result<Self> {
+        let msg_type = message.msg_type;
+        let string = || {
+            message
+                .parse_payload_str()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+        };
+        let list = || {
+            message
+                .parse_payload_list()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+        };
+
+        Ok(match msg_type {
+            XsMessageType::Control => XsResponse::Control(message.payload.clone()),
+            XsMessageType::Directory => XsResponse::Directory(
+                list()?
+                    .iter()
+                    // convert &str to Box<str>
+                    .map(|s| s.to_string().into_boxed_str())
+                    .collect(),
+            ),
+            XsMessageType::Read => XsResponse::Read(message.payload.clone()),
+            XsMessageType::GetPerms => XsResponse::GetPerms(message.parse_payload_perms()?),
+            XsMessageType::TransactionStart => {
+                XsResponse::TransactionStart(message.parse_payload_int()?)
+            }
+            XsMessageType::GetDomainPath => {
+                XsResponse::GetDomainPath(string()?.unwrap_or_default().into())
+            }
+            XsMessageType::IsDomainIntroduced => {
+                XsResponse::IsDomainIntroduced(message.parse_payload_bool()?)
+            }
+            XsMessageType::GetFeature => XsResponse::GetFeature(message.parse_payload_int()?),
+            XsMessageType::GetQuota => XsResponse::GetQuota(string()?.unwrap_or_default().into()),
+            XsMessageType::DirectoryPart => {
+                let (generation, chunk) = message.parse_directory_part()?;
+
+                XsResponse::DirectoryPart {
+                    generation: parse_arg(msg_type, generation)?,
+                    chunk: chunk.into(),
+                }
+            }
+            XsMessageType::WatchEvent => match list()?[..] {
+                [path, token] => XsResponse::WatchEvent {
+                    path: path.into(),
+                    token: token.into(),
+                },
+                _ => return Err(invalid_payload(msg_type)),
+            },
+            XsMessageType::Error => {
+                XsResponse::Error(message.errno().ok_or_else(|| invalid_payload(msg_type))?)
+            }
+            XsMessageType::Watch
+            | XsMessageType::Unwatch
+            | XsMessageType::TransactionEnd
+            | XsMessageType::Introduce
+            | XsMessageType::Release
+            | XsMessageType::Write
+            | XsMessageType::Mkdir
+            | XsMessageType::Rm
+            | XsMessageType::SetPerms
+            | XsMessageType::Resume
+            | XsMessageType::SetTarget
+            | XsMessageType::ResetWatches
+            | XsMessageType::SetFeature
+            | XsMessageType::SetQuota => XsResponse::Ok,
+            XsMessageType::Unknown(value) => return Err(unknown_type(value)),
+        })
+    }
+
+    /// Error for a response that doesn't match its request.
+    pub fn unexpected(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Got unexpected response ({self:?})"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::XsAccess;
+
+    fn message(msg_type: XsMessageType, payload: &[u8]) -> XsMessage {
+        XsMessage {
+            msg_type,
+            request_id: 1,
+            tx_id: 0,
+            payload: payload.into(),
+        }
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let requests = [
+            XsRequest::Control {
+                args: vec!["log".into(), "on".into()],
+                data: None,
+            },
+            XsRequest::Control {
+                args: vec![],
+                data: None,
+            },
+            XsRequest::Control {
+                args: vec!["live-update".into(), "-d".into()],
+                data: Some(b"\x7fELF\0\xff".as_slice().into()),
+            },
+            XsRequest::Directory { path: "/a".into() },
+            XsRequest::Read { path: "/a".into() },
+            XsRequest::GetPerms { path: "/a".into() },
+            XsRequest::Watch {
+                path: "/a".into(),
+                token: "t".into(),
+            },
+            XsRequest::Unwatch {
+                path: "/a".into(),
+                token: "t".into(),
+            },
+            XsRequest::TransactionStart,
+            XsRequest::TransactionEnd { commit: true },
+            XsRequest::TransactionEnd { commit: false },
+            XsRequest::Introduce {
+                domid: 1,
+                mfn: u64::MAX,
+                evtchn: 3,
+            },
+            XsRequest::Release { domid: 1 },
+            XsRequest::GetDomainPath { domid: 1 },
+            XsRequest::Write {
+                path: "/a".into(),
+                value: b"\0\xff".as_slice().into(),
+            },
+            XsRequest::Write {
+                path: "/a".into(),
+                value: b"".as_slice().into(),
+            },
+            XsRequest::Mkdir { path: "/a".into() },
+            XsRequest::Rm { path: "/a".into() },
+            XsRequest::SetPerms {
+                path: "/a".into(),
+                perms: vec![
+                    XsPermission {
+                        domid: 0,
+                        access: XsAccess::None,
+                    },
+                    XsPermission {
+                        domid: 1,
+                        access: XsAccess::Both,
+                    },
+                ],
+            },
+            XsRequest::IsDomainIntroduced { domid: 1 },
+            XsRequest::Resume { domid: 1 },
+            XsRequest::SetTarget {
+                domid: 1,
+                target: 2,
+            },
+            XsRequest::ResetWatches,
+            XsRequest::DirectoryPart {
+                path: "/a".into(),
+                offset: 42,
+            },
+            XsRequest::GetFeature { domid: None },
+            XsRequest::GetFeature { domid: Some(1) },
+            XsRequest::SetFeature {
+                domid: 1,
+                features: 3,
+            },
+            XsRequest::GetQuota {
+                domid: None,
+                quota: None,
+            },
+            XsRequest::GetQuota {
+                domid: None,
+                quota: Some("nodes".into()),
+            },
+            XsRequest::GetQuota {
+                domid: Some(1),
+                quota: Some("nodes".into()),
+            },
+            XsRequest::SetQuota {
+                domid: None,
+                quota: "nodes".into(),
+                value: 1000,
+            },
+            XsRequest::SetQuota {
+                domid: Some(1),
+                quota: "nodes".into(),
+                value: 1000,
+            },
+        ];
+
+        for request in requests {
+            let message = request.encode(7, 3);
+            assert_eq!(message.msg_type, request.msg_type());
+            assert_eq!((message.request_id, message.tx_id), (7, 3));
+
+            assert_eq!(XsRequest::decode(&message).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn request_payload() {
+        let request = XsRequest::Watch {
+            path: "/a".into(),
+            token: "t".into(),
+        };
+        assert_eq!(&*request.encode(0, 0).payload, b"/a\0t\0");
+
+        // WRITE value is not NUL terminated.
+        let request = XsRequest::Write {
+            path: "/a".into(),
+            value: b"v".as_slice().into(),
+        };
+        assert_eq!(&*request.encode(0, 0).payload, b"/a\0v");
+    }
+
+    #[test]
+    fn request_malformed() {
+        let malformed = [
+            // Missing NUL after the path.
+            message(XsMessageType::Write, b"/a"),
+            // Non UTF-8 paths.
+            message(XsMessageType::Read, b"/\xff\0"),
+            message(XsMessageType::Write, b"/\xff\0value"),
+            // Wrong number of arguments.
+            message(XsMessageType::Watch, b"/a\0"),
+            message(XsMessageType::SetTarget, b"1\0"),
+            // Invalid arguments.
+            message(XsMessageType::TransactionEnd, b"X\0"),
+            message(XsMessageType::Release, b"70000\0"),
+            message(XsMessageType::DirectoryPart, b"/a\0-1\0"),
+            message(XsMessageType::SetPerms, b"/a\0z0\0"),
+        ];
+
+        for message in malformed {
+            let error = XsRequest::decode(&message).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{message:?}");
+        }
+
+        let error = XsRequest::decode(&message(XsMessageType::Unknown(42), b"")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let responses = [
+            (
+                XsMessageType::Control,
+                XsResponse::Control(b"out\n".as_slice().into()),
+            ),
+            (
+                XsMessageType::Directory,
+                XsResponse::Directory(vec!["a".into(), "b".into()]),
+            ),
+            (XsMessageType::Directory, XsResponse::Directory(vec![])),
+            (
+                XsMessageType::Read,
+                XsResponse::Read(b"\0\xff".as_slice().into()),
+            ),
+            (
+                XsMessageType::GetPerms,
+                XsResponse::GetPerms(vec![XsPermission {
+                    domid: 0,
+                    access: XsAccess::Read,
+                }]),
+            ),
+            (
+                XsMessageType::TransactionStart,
+                XsResponse::TransactionStart(12),
+            ),
+            (
+                XsMessageType::GetDomainPath,
+                XsResponse::GetDomainPath("/local/domain/1".into()),
+            ),
+            (
+                XsMessageType::IsDomainIntroduced,
+                XsResponse::IsDomainIntroduced(true),
+            ),
+            (
+                XsMessageType::IsDomainIntroduced,
+                XsResponse::IsDomainIntroduced(false),
+            ),
+            (XsMessageType::GetFeature, XsResponse::GetFeature(3)),
+            (
+                XsMessageType::GetQuota,
+                XsResponse::GetQuota("nodes watches".into()),
+            ),
+            (
+                XsMessageType::DirectoryPart,
+                XsResponse::DirectoryPart {
+                    generation: 5,
+                    chunk: b"a\0b\0\0".as_slice().into(),
+                },
+            ),
+            (
+                XsMessageType::Watch,
+                XsResponse::WatchEvent {
+                    path: "/a".into(),
+                    token: "t".into(),
+                },
+            ),
+            (XsMessageType::Read, XsResponse::Error(XsErrno::ENOENT)),
+        ];
+
+        for (request_type, response) in responses {
+            let message = response.encode(request_type, 7, 3);
+            assert_eq!(message.tx_id, 3);
+
+            assert_eq!(XsResponse::decode(&message).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn response_ok() {
+        for request_type in [
+            XsMessageType::Watch,
+            XsMessageType::Unwatch,
+            XsMessageType::TransactionEnd,
+            XsMessageType::Introduce,
+            XsMessageType::Release,
+            XsMessageType::Write,
+            XsMessageType::Mkdir,
+            XsMessageType::Rm,
+            XsMessageType::SetPerms,
+            XsMessageType::Resume,
+            XsMessageType::SetTarget,
+            XsMessageType::ResetWatches,
+            XsMessageType::SetFeature,
+            XsMessageType::SetQuota,
+        ] {
+            let message = XsResponse::Ok.encode(request_type, 7, 0);
+            assert_eq!(message.msg_type, request_type);
+            assert_eq!(&*message.payload, b"OK\0");
+
+            assert_eq!(XsResponse::decode(&message).unwrap(), XsResponse::Ok);
+        }
+    }
+
+    #[test]
+    fn response_malformed() {
+        let malformed = [
+            // Missing NUL after the generation.
+            message(XsMessageType::DirectoryPart, b"5"),
+            // Invalid generations.
+            message(XsMessageType::DirectoryPart, b"abc\0a\0\0"),
+            message(XsMessageType::DirectoryPart, b"-1\0a\0\0"),
+            message(XsMessageType::DirectoryPart, b"\xff\0a\0\0"),
+            // Non UTF-8 names.
+            message(XsMessageType::Directory, b"a\0\xff\0"),
+            message(XsMessageType::WatchEvent, b"/\xff\0t\0"),
+            // Missing token.
+            message(XsMessageType::WatchEvent, b"/a\0"),
+            message(XsMessageType::TransactionStart, b"x\0"),
+            message(XsMessageType::IsDomainIntroduced, b"X\0"),
+            message(XsMessageType::GetPerms, b"z0\0"),
+            message(XsMessageType::Error, b"\xff\0"),
+        ];
+
+        for message in malformed {
+            let error = XsResponse::decode(&message).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{message:?}");
+        }
+
+        let error = XsResponse::decode(&message(XsMessageType::Unknown(42), b"")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+}
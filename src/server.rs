@@ -0,0 +1,320 @@
+//! Xenstore server, speaking the wire protocol over Unix sockets.
+//!
+//! Serves a [XsMemoryStore] to any xenstore client, e.g [crate::unix::XsUnix] or
+//! [crate::tokio::XsTokio] (through `XENSTORED_PATH`) or C tools like `xenstore-ls`,
+//! for integration tests without a Xen host.
+//!
+//! ```no_run
+//! use tokio::net::UnixListener;
+//! use xenstore_rs::{memory::XsMemoryStore, server::XsServer};
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let server = XsServer::new(XsMemoryStore::new());
+//!
+//! // Connections are made on behalf of dom0.
+//! server.serve(UnixListener::bind("/tmp/xenstored.sock")?, 0).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UnixListener,
+    sync::mpsc,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{
+    codec::XsMessageCodec,
+    memory::{
+        engine::{self, ConnectionId, Engine, NodeOperation, NodeResult},
+        XsMemoryStore,
+    },
+    protocol::{XsMessage, XsMessageType, XsRequest, XsResponse},
+    XsErrno,
+};
+
+/// Xenstore server.
+#[derive(Clone, Default)]
+pub struct XsServer {
+    store: XsMemoryStore,
+}
+
+/// Removes a connection from the engine on [Drop].
+struct ConnectionGuard {
+    store: XsMemoryStore,
+    id: ConnectionId,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.store.engine().disconnect(self.id);
+    }
+}
+
+impl XsServer {
+    /// Create a server serving `store`.
+    pub fn new(store: XsMemoryStore) -> Self {
+        Self { store }
+    }
+
+    /// Get the store served by this server.
+    pub fn store(&self) -> &XsMemoryStore {
+        &self.store
+    }
+
+    /// Accept connections on `listener` forever, each being served on behalf
+    /// of domain `domid` (dom0 is privileged).
+    ///
+    /// Use different listeners to serve different domains.
+    pub async fn serve(&self, listener: UnixListener, domid: u16) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream, domid).await {
+                    log::warn!("Connection failure: {e}");
+                }
+            });
+        }
+    }
+
+    /// Serve a single connection on behalf of domain `domid` until it is closed.
+    ///
+    /// Watches and transactions of the connection are removed once closed.
+    pub async fn serve_connection<S>(&self, stream: S, domid: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = FramedRead::new(reader, XsMessageCodec::new());
+        let mut writer = FramedWrite::new(writer, XsMessageCodec::new());
+
+        // Responses and watch events, in order.
+        let (sender, mut receiver) = mpsc::unbounded_channel::<XsMessage>();
+
+        let guard = {
+            let sender = sender.clone();
+
+            let sink = Box::new(move |path: &str, token: &str| {
+                // Connection may be closing.
+                let event = XsResponse::WatchEvent {
+                    path: path.into(),
+                    token: token.into(),
+                };
+
+                sender
+                    .send(event.encode(XsMessageType::WatchEvent, 0, 0))
+                    .ok();
+            });
+
+            ConnectionGuard {
+                store: self.store.clone(),
+                id: self.store.engine().connect(domid, sink),
+            }
+        };
+
+        let reading = async move {
+            loop {
+                let request = match reader.next().await {
+                    Some(Ok(request)) => request,
+                    // Connection closed.
+                    None => break,
+                    Some(Err(e)) if is_closed(&e) => break,
+                    Some(Err(e)) => return Err(e),
+                };
+
+                log::trace!("Request {request:?}");
+
+                let responses = handle_request(&mut self.store.engine(), guard.id, &request);
+
+                for response in responses {
+                    // Can't fail, receiver lives as long as the connection.
+                    sender.send(response).ok();
+                }
+            }
+
+            // Closing the connection drops the sender of its event sink.
+            drop(guard);
+            Ok(())
+        };
+
+        let writing = async {
+            while let Some(message) = receiver.recv().await {
+                match writer.send(message).await {
+                    Ok(()) => {}
+                    // Peer is gone, reading will stop too.
+                    Err(e) if is_closed(&e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            Ok(())
+        };
+
+        tokio::try_join!(reading, writing).map(|_| ())
+    }
+}
+
+/// Check if an error means that the peer closed the connection.
+fn is_closed(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Handle a request, giving the messages to send back (the response and
+/// possibly watch events).
+fn handle_request(
+    engine: &mut Engine,
+    connection: ConnectionId,
+    message: &XsMessage,
+) -> Vec<XsMessage> {
+    let mut messages = Vec::with_capacity(1);
+
+    let request = XsRequest::decode(message);
+    let response = match &request {
+        Ok(request) => process_request(engine, connection, message.tx_id, request)
+            .unwrap_or_else(XsResponse::Error),
+        // Like xenstored, unknown requests are not supported rather than invalid.
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => XsResponse::Error(XsErrno::ENOSYS),
+        Err(_) => XsResponse::Error(XsErrno::EINVAL),
+    };
+
+    messages.push(response.encode(message.msg_type, message.request_id, message.tx_id));
+
+    // Watches fire once registered, after being acknowledged.
+    if let (Ok(XsRequest::Watch { path, token }), XsResponse::Ok) = (&request, &response) {
+        let event = XsResponse::WatchEvent {
+            path: path.clone(),
+            token: token.clone(),
+        };
+
+        messages.push(event.encode(XsMessageType::WatchEvent, 0, 0));
+    }
+
+    messages
+}
+
+/// Process a request, giving its response.
+fn process_request(
+    engine: &mut Engine,
+    connection: ConnectionId,
+    tx_id: u32,
+    request: &XsRequest,
+) -> Result<XsResponse, XsErrno> {
+    let mut node_operation =
+        |path: &str, operation| engine.node_operation(connection, tx_id, path, operation);
+
+    match request {
+        XsRequest::Directory { path } => match node_operation(path, NodeOperation::Directory)? {
+            NodeResult::List(children) => Ok(XsResponse::Directory(children)),
+            _ => unreachable!("DIRECTORY returns a list"),
+        },
+        XsRequest::DirectoryPart { path, offset } => {
+            match node_operation(path, NodeOperation::DirectoryPart(*offset))? {
+                NodeResult::DirectoryPart(generation, chunk) => Ok(XsResponse::DirectoryPart {
+                    generation,
+                    chunk: chunk.into(),
+                }),
+                _ => unreachable!("DIRECTORY_PART returns a directory part"),
+            }
+        }
+        XsRequest::Read { path } => match node_operation(path, NodeOperation::Read)? {
+            NodeResult::Value(value) => Ok(XsResponse::Read(value.into())),
+            _ => unreachable!("READ returns a value"),
+        },
+        XsRequest::Write { path, value } => {
+            node_operation(path, NodeOperation::Write(value))?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::Mkdir { path } => {
+            node_operation(path, NodeOperation::Mkdir)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::Rm { path } => {
+            node_operation(path, NodeOperation::Rm)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::GetPerms { path } => match node_operation(path, NodeOperation::GetPerms)? {
+            NodeResult::Perms(perms) => Ok(XsResponse::GetPerms(perms)),
+            _ => unreachable!("GET_PERMS returns permissions"),
+        },
+        XsRequest::SetPerms { path, perms } => {
+            node_operation(path, NodeOperation::SetPerms(perms))?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::Watch { path, token } => {
+            engine.watch(connection, path, token)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::Unwatch { path, token } => {
+            engine.unwatch(connection, path, token)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::ResetWatches => {
+            engine.reset_watches(connection)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::TransactionStart => Ok(XsResponse::TransactionStart(
+            engine.transaction_start(connection)?,
+        )),
+        XsRequest::TransactionEnd { commit } => {
+            engine.transaction_end(connection, tx_id, *commit)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::GetDomainPath { domid } => Ok(XsResponse::GetDomainPath(
+            engine::domain_path(*domid).into(),
+        )),
+        XsRequest::Introduce { domid, mfn, evtchn } => {
+            engine.introduce(connection, *domid, *mfn, *evtchn)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::Release { domid } => {
+            engine.release(connection, *domid)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::IsDomainIntroduced { domid } => Ok(XsResponse::IsDomainIntroduced(
+            engine.is_domain_introduced(connection, *domid)?,
+        )),
+        XsRequest::Resume { domid } => {
+            engine.resume(connection, *domid)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::SetTarget { domid, target } => {
+            engine.set_target(connection, *domid, *target)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::GetFeature { domid } => Ok(XsResponse::GetFeature(
+            engine.get_features(connection, *domid)?,
+        )),
+        XsRequest::SetFeature { domid, features } => {
+            engine.set_features(connection, *domid, *features)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::GetQuota { quota: None, .. } => Ok(XsResponse::GetQuota(
+            engine.quota_names(connection)?.join(" ").into(),
+        )),
+        XsRequest::GetQuota {
+            domid,
+            quota: Some(quota),
+        } => {
+            let value = engine.get_quota(connection, *domid, quota)?;
+            Ok(XsResponse::GetQuota(value.to_string().into()))
+        }
+        XsRequest::SetQuota {
+            domid,
+            quota,
+            value,
+        } => {
+            engine.set_quota(connection, *domid, quota, *value)?;
+            Ok(XsResponse::Ok)
+        }
+        XsRequest::Control { .. } => Err(XsErrno::ENOSYS),
+    }
+}
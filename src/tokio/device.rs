@@ -23,7 +23,7 @@ use tokio::{
     task,
 };
 
-use crate::wire::XENBUS_DEVICE_PATH;
+use crate::protocol::XENBUS_DEVICE_PATH;
 
 pub struct XsDevice(AsyncFd<File>);
 
@@ -1,28 +1,78 @@
-use std::{cell::Cell, collections::HashMap};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    future::{poll_fn, Future},
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail};
+use futures::{SinkExt, StreamExt};
 use log::{debug, error, info, warn};
 use tokio::{
     io::{self, AsyncRead, AsyncWrite, Error, ErrorKind},
-    sync::{mpsc, oneshot},
+    net::UnixStream,
+    sync::{broadcast, mpsc, oneshot, watch},
 };
+use tokio_util::codec::{FramedRead, FramedWrite};
 use uuid::Uuid;
 
-use crate::wire::{XsMessage, XsMessageType};
+use super::{device::XsDevice, XsTokioBuilder, XsTokioEvent};
+use crate::{
+    codec::XsMessageCodec,
+    protocol::{XsMessage, XsMessageType, XsRequest, XsResponse},
+};
+
+/// Delay before the first reconnection attempt, doubled after each failure.
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Maximum delay between two reconnection attempts.
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Why the connection to xenstored has been closed for good (if it has).
+pub type XsClosedReason = Option<Arc<io::Error>>;
+
+/// Make a copy of a shared `error`, keeping its kind and message.
+pub fn shared_error(error: &Arc<io::Error>) -> io::Error {
+    Error::new(error.kind(), error.clone())
+}
+
+/// Stream to xenstored (e.g socket or xenbus device).
+pub trait XsStream: AsyncRead + AsyncWrite + Send + Unpin {}
 
-/// Maximum number of pending requests.
-const MAX_REQUEST_COUNT: usize = 32;
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> XsStream for S {}
+
+/// Open a stream to xenstored, attempting in order :
+///  - `XENSTORED_PATH` or `/run/xenstored/socket` (unix domain socket)
+///  - [crate::protocol::XENBUS_DEVICE_PATH] (xenstore device)
+pub async fn connect() -> io::Result<Box<dyn XsStream>> {
+    let xsd_path =
+        env::var("XENSTORED_PATH").unwrap_or_else(|_| "/run/xenstored/socket".to_string());
+
+    // Use xenstored socket first
+    if let Ok(stream) = UnixStream::connect(xsd_path).await {
+        Ok(Box::new(stream))
+    } else {
+        Ok(Box::new(XsDevice::new().await?))
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct XsWatchToken(Uuid);
 
 pub struct XsTokioRequest {
     pub request: XsMessage,
-    pub response_sender: oneshot::Sender<XsMessage>,
+    pub response_sender: oneshot::Sender<io::Result<XsMessage>>,
 }
 
 pub enum XsTokioMessage {
+    /// Request whose caller waits for the response, dropped if it gave up
+    /// (e.g timed out) while the request was queued.
     Request(XsTokioRequest),
+    /// Request sent regardless of any caller, whose response is discarded
+    /// (e.g transaction abort on [Drop]).
+    Detached(XsMessage),
     WatchSubscribe {
         path: Box<str>,
         event_sender: mpsc::Sender<Box<str>>,
@@ -31,8 +81,19 @@ pub enum XsTokioMessage {
     WatchUnsubscribe(XsWatchToken),
 }
 
+/// Handle to the underlying task.
+#[derive(Clone, Debug)]
+pub struct XsTokioHandle {
+    /// Queue of the messages, bounded to apply backpressure.
+    pub messages: mpsc::Sender<XsTokioMessage>,
+    /// Messages sent on [Drop] (e.g unwatch), which can't wait for room in the queue.
+    pub cleanup: mpsc::UnboundedSender<XsTokioMessage>,
+    pub events: broadcast::Sender<XsTokioEvent>,
+    pub closed: watch::Receiver<XsClosedReason>,
+}
+
 enum XsTokioTask {
-    Request(oneshot::Sender<XsMessage>),
+    Request(oneshot::Sender<io::Result<XsMessage>>),
     WatchSubscribe {
         subscriber_info: WatchSubscriberInfo,
         result_channel: oneshot::Sender<io::Result<XsWatchToken>>,
@@ -48,13 +109,29 @@ struct WatchSubscriberInfo {
 }
 
 struct XsTokioState {
-    pending_tasks: [Cell<Option<XsTokioTask>>; MAX_REQUEST_COUNT],
+    /// Tasks in flight by request id.
+    pending_tasks: HashMap<u32, XsTokioTask>,
+    /// Requests in flight whose caller gave up, their id can't be reused until
+    /// their (late) response comes.
+    abandoned_requests: HashSet<u32>,
+    next_request_id: u32,
     watch_subscribers: HashMap<Uuid, WatchSubscriberInfo>,
-    task_count: usize,
+    options: XsTokioBuilder,
 
     request_channel: mpsc::Sender<XsMessage>,
-    response_channel: mpsc::Receiver<XsMessage>,
-    message_receiver: mpsc::UnboundedReceiver<XsTokioMessage>,
+    response_channel: mpsc::Receiver<io::Result<XsMessage>>,
+    message_receiver: mpsc::Receiver<XsTokioMessage>,
+    cleanup_receiver: mpsc::UnboundedReceiver<XsTokioMessage>,
+    event_sender: broadcast::Sender<XsTokioEvent>,
+    closed_sender: watch::Sender<XsClosedReason>,
+}
+
+/// Why [XsTokioState::serve] stopped.
+enum ServeEnd {
+    /// All the [super::XsTokio] (and watches) are gone.
+    Closed,
+    /// Connection to xenstored has been lost.
+    Lost(Arc<io::Error>),
 }
 
 fn find_suitable_token<V>(watch_subscribers: &HashMap<Uuid, V>) -> XsWatchToken {
@@ -68,29 +145,78 @@ fn find_suitable_token<V>(watch_subscribers: &HashMap<Uuid, V>) -> XsWatchToken
     }
 }
 
+/// Wait for a request in flight to be cancelled (e.g timed out), giving its id.
+fn cancelled_request(
+    pending_tasks: &mut HashMap<u32, XsTokioTask>,
+) -> impl Future<Output = u32> + '_ {
+    poll_fn(move |cx| {
+        for (&req_id, task) in pending_tasks.iter_mut() {
+            if let XsTokioTask::Request(sender) = task {
+                if sender.poll_closed(cx).is_ready() {
+                    return Poll::Ready(req_id);
+                }
+            }
+        }
+
+        Poll::Pending
+    })
+}
+
+/// Send a message to xenstored.
+///
+/// A failure means that the connection is lost, which is reported by the stream
+/// tasks along with its cause, failing the tasks in flight (including this one).
+async fn send(request_channel: &mpsc::Sender<XsMessage>, message: XsMessage) {
+    if request_channel.send(message).await.is_err() {
+        debug!("Sending message on a lost connection");
+    }
+}
+
 impl XsTokioState {
+    /// Allocate a request id that is not in flight.
+    fn allocate_request_id(&mut self) -> u32 {
+        // The number of tasks in flight is bounded, so there is always a free one.
+        loop {
+            let req_id = self.next_request_id;
+            self.next_request_id = self.next_request_id.wrapping_add(1);
+
+            if !self.pending_tasks.contains_key(&req_id)
+                && !self.abandoned_requests.contains(&req_id)
+            {
+                return req_id;
+            }
+        }
+    }
+
     async fn process_message(&mut self, message: XsTokioMessage) -> anyhow::Result<()> {
-        // Find a available task slot.
-        let Some((req_id, slot)) = self
-            .pending_tasks
-            .iter_mut()
-            .map(|slot| slot.get_mut())
-            .enumerate()
-            .find(|(_, slot)| slot.is_none())
-        else {
-            bail!("No available slot");
-        };
+        if let XsTokioMessage::Request(request) = &message {
+            if request.response_sender.is_closed() {
+                // Caller gave up, the request must not be applied behind its back.
+                debug!("Dropping request cancelled while queued");
+                return Ok(());
+            }
+        }
+
+        let req_id = self.allocate_request_id();
 
-        match message {
+        let task = match message {
             XsTokioMessage::Request(XsTokioRequest {
                 mut request,
                 response_sender,
             }) => {
-                request.request_id = req_id as u32;
+                // tx_id is already set by the caller (if in a transaction).
+                request.request_id = req_id;
 
-                self.request_channel.send(request).await?;
-                *slot = Some(XsTokioTask::Request(response_sender));
-                self.task_count += 1;
+                send(&self.request_channel, request).await;
+                XsTokioTask::Request(response_sender)
+            }
+            XsTokioMessage::Detached(mut request) => {
+                request.request_id = req_id;
+                send(&self.request_channel, request).await;
+
+                // Nobody waits for it, discard the response once it comes.
+                self.abandoned_requests.insert(req_id);
+                return Ok(());
             }
             XsTokioMessage::WatchSubscribe {
                 path,
@@ -100,22 +226,18 @@ impl XsTokioState {
                 let token = find_suitable_token(&self.watch_subscribers);
 
                 // Make the actual WATCH command
-                self.request_channel
-                    .send(XsMessage::from_string_slice(
-                        XsMessageType::Watch,
-                        req_id as u32,
-                        &[&path, &token.0.to_string()],
-                        true,
-                    ))
-                    .await?;
+                let request = XsRequest::Watch {
+                    path: path.clone(),
+                    token: token.0.to_string().into(),
+                };
+                send(&self.request_channel, request.encode(req_id, 0)).await;
 
                 // Wait until we got confirmation of the WATCH command by upstream.
-                *slot = Some(XsTokioTask::WatchSubscribe {
+                XsTokioTask::WatchSubscribe {
                     subscriber_info: WatchSubscriberInfo { channel, path },
                     result_channel,
                     token,
-                });
-                self.task_count += 1;
+                }
             }
             XsTokioMessage::WatchUnsubscribe(token) => {
                 let Some(WatchSubscriberInfo { path, .. }) = self.watch_subscribers.get(&token.0)
@@ -124,21 +246,18 @@ impl XsTokioState {
                 };
 
                 // Make the actual UNWATCH command
-                self.request_channel
-                    .send(XsMessage::from_string_slice(
-                        XsMessageType::Unwatch,
-                        req_id as u32,
-                        &[path, &token.0.to_string()],
-                        true,
-                    ))
-                    .await?;
+                let request = XsRequest::Unwatch {
+                    path: path.clone(),
+                    token: token.0.to_string().into(),
+                };
+                send(&self.request_channel, request.encode(req_id, 0)).await;
 
                 // Wait until we got confirmation of the WATCH command by upstream.
-                *slot = Some(XsTokioTask::WatchUnsubscribe(token));
-                self.task_count += 1;
+                XsTokioTask::WatchUnsubscribe(token)
             }
-        }
+        };
 
+        self.pending_tasks.insert(req_id, task);
         Ok(())
     }
 
@@ -148,24 +267,34 @@ impl XsTokioState {
             return self.process_watch_entry(response).await;
         }
 
+        if let XsMessageType::Unknown(msg_type) = response.msg_type {
+            // Can't be a response to one of our requests (e.g sent by a newer xenstored).
+            bail!("Got message of unknown type ({msg_type}), ignoring it")
+        }
+
         // All other requests have a req_id and is solicitated,
         // thus they have a related pending_tasks entry.
 
-        // Take a reference the the task slot (if any).
-        let Some(slot) = self.pending_tasks.get_mut(response.request_id as usize) else {
-            bail!("Invalid req_id received")
-        };
+        let task = match self.pending_tasks.remove(&response.request_id) {
+            Some(task) => task,
+            None if self.abandoned_requests.remove(&response.request_id) => {
+                debug!("Got late response to a cancelled request");
 
-        // Take it (leaving None at the place).
-        let Some(task) = slot.take() else {
-            bail!("No related request to this req_id")
+                // Process it alike (e.g RESET_WATCHES), discarding the response.
+                XsTokioTask::Request(oneshot::channel().0)
+            }
+            None => bail!("No related request to this req_id"),
         };
-        self.task_count -= 1;
 
         match task {
             XsTokioTask::Request(sender) => {
+                if response.msg_type == XsMessageType::ResetWatches {
+                    // All watches are gone upstream, drop their subscribers.
+                    self.watch_subscribers.clear();
+                }
+
                 // Usual request, forward response to caller (even if it is Error variant).
-                sender.send(response).ok();
+                sender.send(Ok(response)).ok();
             }
             XsTokioTask::WatchSubscribe {
                 token,
@@ -184,10 +313,19 @@ impl XsTokioState {
                         }
 
                         // Report that things got successful.
-                        result_channel.send(Ok(token)).ok();
+                        if result_channel.send(Ok(token)).is_err() {
+                            // Caller gave up meanwhile, don't leak the watch.
+                            self.process_message(XsTokioMessage::WatchUnsubscribe(token))
+                                .await?;
+                        }
                     }
                     XsMessageType::Error => {
-                        result_channel.send(Err(response.parse_error())).ok();
+                        result_channel
+                            .send(Err(response.parse_error(
+                                XsMessageType::Watch,
+                                Some(&subscriber_info.path),
+                            )))
+                            .ok();
                     }
                     response => {
                         result_channel
@@ -218,14 +356,14 @@ impl XsTokioState {
     }
 
     async fn process_watch_entry(&mut self, msg: XsMessage) -> Result<(), anyhow::Error> {
-        let [value, token] = msg.parse_payload_list()?[..] else {
+        let XsResponse::WatchEvent { path, token } = XsResponse::decode(&msg)? else {
             bail!("Invalid watch event payload received")
         };
 
-        let uuid = Uuid::try_parse(token).map_err(|_| anyhow!("Got non-UUID token"))?;
+        let uuid = Uuid::try_parse(&token).map_err(|_| anyhow!("Got non-UUID token"))?;
 
         if let Some(subscriber) = self.watch_subscribers.get(&uuid) {
-            if let Err(e) = subscriber.channel.send(value.into()).await {
+            if let Err(e) = subscriber.channel.send(path).await {
                 warn!("Lost watch subscriber: {e}");
 
                 // Subscriber is dead, remove it.
@@ -238,59 +376,222 @@ impl XsTokioState {
         Ok(())
     }
 
-    async fn run(&mut self) {
+    /// Process messages and responses until the connection is lost
+    /// (or there is nothing left to serve).
+    async fn serve(&mut self) -> ServeEnd {
         loop {
-            if self.task_count == MAX_REQUEST_COUNT {
-                // We can't process another task, only interface responses.
-                debug!("Too much tasks");
-                let Some(response) = self.response_channel.recv().await else {
-                    break;
-                };
+            // Once too many tasks are in flight, leave the messages in the queue
+            // (making the callers wait for room) until some responses come.
+            // Abandoned requests are still in flight, a hung xenstored must not
+            // make them pile up.
+            let in_flight = self.pending_tasks.len() + self.abandoned_requests.len();
+            let can_process = in_flight < self.options.max_requests;
+
+            // Biased so that the cleanup messages (e.g transaction aborts) queued
+            // before the last handle went away are sent rather than lost.
+            tokio::select! {
+                biased;
+
+                Some(message) = self.cleanup_receiver.recv(), if can_process => {
+                    if let Err(e) = self.process_message(message).await {
+                        warn!("Process message failure: {e}")
+                    }
+                }
+                response = self.response_channel.recv() => {
+                    let response = match response {
+                        Some(Ok(response)) => response,
+                        Some(Err(e)) => return ServeEnd::Lost(Arc::new(e)),
+                        None => return ServeEnd::Lost(Arc::new(stream_tasks_died())),
+                    };
+
+                    if let Err(e) = self.process_response(response).await {
+                        warn!("Process response failure: {e}")
+                    }
+                },
+                req_id = cancelled_request(&mut self.pending_tasks) => {
+                    // Keep the id (and the slot) until the response comes.
+                    self.pending_tasks.remove(&req_id);
+                    self.abandoned_requests.insert(req_id);
+                }
+                message = self.message_receiver.recv(), if can_process => {
+                    let Some(message) = message else {
+                        return ServeEnd::Closed;
+                    };
 
-                if let Err(e) = self.process_response(response).await {
-                    warn!("Process response failure: {e}")
+                    if let Err(e) = self.process_message(message).await {
+                        warn!("Process message failure: {e}")
+                    }
                 }
-            } else {
-                tokio::select! {
-                    Some(response) = self.response_channel.recv() => {
-                        if let Err(e) = self.process_response(response).await {
-                            warn!("Process response failure: {e}")
-                        }
-                    },
-                    Some(message) = self.message_receiver.recv() => {
-                        if let Err(e) = self.process_message(message).await {
-                            warn!("Process message failure: {e}")
+            }
+        }
+    }
+
+    /// Fail the tasks in flight with the `error` that caused the connection loss.
+    fn fail_pending_tasks(&mut self, error: &Arc<io::Error>) {
+        self.abandoned_requests.clear();
+
+        for (_, task) in self.pending_tasks.drain() {
+            match task {
+                XsTokioTask::Request(sender) => {
+                    sender.send(Err(shared_error(error))).ok();
+                }
+                XsTokioTask::WatchSubscribe { result_channel, .. } => {
+                    result_channel.send(Err(shared_error(error))).ok();
+                }
+                // Watch is gone with the connection, don't restore it.
+                XsTokioTask::WatchUnsubscribe(token) => {
+                    self.watch_subscribers.remove(&token.0);
+                }
+            }
+        }
+    }
+
+    /// Register the watches again (with the same tokens) on a new connection.
+    async fn restore_watches(&mut self) -> anyhow::Result<()> {
+        let tokens: Vec<Uuid> = self.watch_subscribers.keys().copied().collect();
+
+        for uuid in tokens {
+            let path = self.watch_subscribers[&uuid].path.clone();
+
+            let request = XsRequest::Watch {
+                path: path.clone(),
+                token: uuid.to_string().into(),
+            };
+            send(&self.request_channel, request.encode(0, 0)).await;
+
+            // Nothing else is in flight, wait for the response here.
+            loop {
+                let response = self
+                    .response_channel
+                    .recv()
+                    .await
+                    .unwrap_or_else(|| Err(stream_tasks_died()))?;
+
+                match response.msg_type {
+                    // Xenstored fires the watch once registered (which may come first).
+                    XsMessageType::WatchEvent => {
+                        if let Err(e) = self.process_watch_entry(response).await {
+                            warn!("Process watch event failure: {e}")
                         }
                     }
-                    // In case we get a None, something is dead in the loop, stop here.
-                    else => break,
+                    XsMessageType::Watch => break,
+                    XsMessageType::Error => {
+                        warn!("Failed to restore watch on {path}");
+
+                        // Subscriber will yield None.
+                        self.watch_subscribers.remove(&uuid);
+                        break;
+                    }
+                    XsMessageType::Unknown(msg_type) => {
+                        warn!("Got message of unknown type ({msg_type}), ignoring it")
+                    }
+                    msg_type => bail!("Got unexpected response to WATCH ({msg_type:?})"),
                 }
             }
         }
 
+        Ok(())
+    }
+
+    /// Reopen the connection to xenstored and restore the watches.
+    ///
+    /// Retry until it succeeds, or there is nothing left to serve (returning false).
+    async fn reopen(&mut self) -> bool {
+        let mut backoff = RECONNECT_BACKOFF;
+
+        loop {
+            if self.message_receiver.is_closed() {
+                return false;
+            }
+
+            match connect().await {
+                Ok(stream) => {
+                    (self.request_channel, self.response_channel) =
+                        spawn_stream_tasks(stream, self.options.stream_queue_depth);
+
+                    match self.restore_watches().await {
+                        Ok(()) => return true,
+                        Err(e) => warn!("Reconnection failure: {e}"),
+                    }
+                }
+                Err(e) => debug!("Reconnection failure: {e}"),
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+        }
+    }
+
+    async fn run(&mut self) {
+        while let ServeEnd::Lost(error) = self.serve().await {
+            warn!("Connection to xenstored lost: {error}");
+            self.fail_pending_tasks(&error);
+
+            if !self.options.reconnect {
+                // Let the handles know why before watchers yield None.
+                self.closed_sender.send_replace(Some(error));
+                break;
+            }
+
+            self.event_sender.send(XsTokioEvent::Disconnected).ok();
+
+            if !self.reopen().await {
+                break;
+            }
+
+            info!("Reconnected to xenstored");
+            self.event_sender.send(XsTokioEvent::Reconnected).ok();
+        }
+
         info!("Communication channel died");
     }
 }
 
-pub fn launch_xenstore_task<S>(xs_stream: S) -> mpsc::UnboundedSender<XsTokioMessage>
+fn stream_tasks_died() -> io::Error {
+    Error::new(ErrorKind::BrokenPipe, "Stream tasks died")
+}
+
+/// Spawn the tasks reading and writing messages on `xs_stream`.
+///
+/// The first I/O error (if any) is reported through the response channel.
+fn spawn_stream_tasks<S>(
+    xs_stream: S,
+    queue_depth: usize,
+) -> (
+    mpsc::Sender<XsMessage>,
+    mpsc::Receiver<io::Result<XsMessage>>,
+)
 where
     S: AsyncRead + AsyncWrite + Send + 'static,
 {
-    let (mut rx, mut tx) = io::split(xs_stream);
-    let (response_tx, response_rx) = mpsc::channel(4);
-    let (request_tx, mut request_rx) = mpsc::channel(4);
+    let (rx, tx) = io::split(xs_stream);
+    let mut rx = FramedRead::new(rx, XsMessageCodec::new());
+    let mut tx = FramedWrite::new(tx, XsMessageCodec::new());
+    let (response_tx, response_rx) = mpsc::channel(queue_depth);
+    let (request_tx, mut request_rx) = mpsc::channel(queue_depth);
+
+    let error_tx = response_tx.clone();
 
     // Message receiver task
     tokio::spawn(async move {
-        while let Ok(message) = XsMessage::read_message_async(&mut rx).await {
-            debug!("< {message:?}");
+        let error = loop {
+            match rx.next().await {
+                Some(Ok(message)) => {
+                    debug!("< {message:?}");
 
-            if response_tx.send(message).await.is_err() {
-                break;
+                    if response_tx.send(Ok(message)).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Err(e)) => break e,
+                None => {
+                    break Error::new(ErrorKind::UnexpectedEof, "Connection closed by xenstored")
+                }
             }
-        }
+        };
 
-        error!("Read message failure");
+        error!("Read message failure: {error}");
+        response_tx.send(Err(error)).await.ok();
     });
 
     // Message sender task
@@ -298,25 +599,48 @@ where
         while let Some(message) = request_rx.recv().await {
             debug!("> {message:?}");
 
-            if let Err(e) = XsMessage::write_message_async(&message, &mut tx).await {
-                error!("Write message failure {e}");
+            if let Err(e) = tx.send(message).await {
+                error!("Write message failure: {e}");
+                error_tx.send(Err(e)).await.ok();
                 break;
             }
         }
     });
 
-    let (sender, receiver) = mpsc::unbounded_channel();
+    (request_tx, response_rx)
+}
+
+/// Launch the task serving requests over `xs_stream` with `options`.
+pub fn launch_xenstore_task<S>(xs_stream: S, options: &XsTokioBuilder) -> XsTokioHandle
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (request_tx, response_rx) = spawn_stream_tasks(xs_stream, options.stream_queue_depth);
+    let (messages, message_receiver) = mpsc::channel(options.request_queue_depth);
+    let (cleanup, cleanup_receiver) = mpsc::unbounded_channel();
+    let (events, _) = broadcast::channel(16);
+    let (closed_sender, closed) = watch::channel(None);
 
     let mut state = XsTokioState {
-        pending_tasks: [const { Cell::new(None) }; MAX_REQUEST_COUNT],
+        pending_tasks: HashMap::new(),
+        abandoned_requests: HashSet::new(),
+        next_request_id: 0,
         watch_subscribers: HashMap::new(),
-        task_count: 0,
+        options: options.clone(),
         request_channel: request_tx,
         response_channel: response_rx,
-        message_receiver: receiver,
+        message_receiver,
+        cleanup_receiver,
+        event_sender: events.clone(),
+        closed_sender,
     };
 
     tokio::spawn(async move { state.run().await });
 
-    sender
+    XsTokioHandle {
+        messages,
+        cleanup,
+        events,
+        closed,
+    }
 }
@@ -4,139 +4,581 @@
 //!
 //! This implementation uses a underlying task to multiplex the concurrent
 //! accesses and manage watchers. If this underlying task dies (e.g dead xenstore socket),
-//! all future operations will fail with [io::ErrorKind::BrokenPipe] and all watchers
-//! will yield [None].
+//! all pending and future operations will fail with the cause of the failure and all
+//! watchers will yield [None] (see [XsTokio::closed] and [XsTokioWatch::error]).
+//!
+//! Unless opened with [XsTokioBuilder::reconnect], in which case the connection is
+//! reopened (e.g xenstored restart or live update) and the watches registered again,
+//! only the requests in flight failing meanwhile (see [XsTokio::events]).
 
 mod device;
 mod interface;
-mod wire_async;
 
 use std::{
-    env,
+    future::Future,
     io::{self, ErrorKind},
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use futures::Stream;
-use tokio::{
-    net::UnixStream,
-    sync::{mpsc, oneshot},
-};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
-use interface::{launch_xenstore_task, XsTokioMessage, XsTokioRequest, XsWatchToken};
+use interface::{
+    connect, launch_xenstore_task, shared_error, XsTokioHandle, XsTokioMessage, XsTokioRequest,
+    XsWatchToken,
+};
 
 use crate::{
-    wire::{XsMessage, XsMessageType},
-    AsyncWatch, AsyncXs,
+    control::XsControlCommand,
+    protocol::{XsDirectoryPart, XsMessage, XsRequest, XsResponse},
+    AsyncWatch, AsyncXs, AsyncXsControl, AsyncXsDomainControl, AsyncXsTransaction,
+    AsyncXsTransactionSpan, XsErrno, XsPermission,
 };
 
 /// Tokio Xenstore implementation.
 ///
 /// It can be cloned and used concurrently by multiple tasks.
 #[derive(Clone, Debug)]
-pub struct XsTokio(mpsc::UnboundedSender<XsTokioMessage>);
+pub struct XsTokio {
+    handle: XsTokioHandle,
+    /// Capacity of the event queue of a watch.
+    watch_queue_depth: usize,
+    /// Time to wait for a response (forever if none).
+    timeout: Option<Duration>,
+    /// Transaction in which the requests are made (0 if none).
+    tx_id: u32,
+}
+
+/// Connection event, see [XsTokio::events].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XsTokioEvent {
+    /// Connection to xenstored has been lost, the requests in flight failed.
+    Disconnected,
+    /// Connection to xenstored has been reopened and the watches registered again.
+    Reconnected,
+}
+
+/// [XsTokio] builder, to open it with custom options.
+#[derive(Clone, Debug)]
+pub struct XsTokioBuilder {
+    reset_watches: bool,
+    reconnect: bool,
+    max_requests: usize,
+    request_queue_depth: usize,
+    stream_queue_depth: usize,
+    watch_queue_depth: usize,
+    request_timeout: Option<Duration>,
+}
+
+impl Default for XsTokioBuilder {
+    fn default() -> Self {
+        Self {
+            reset_watches: false,
+            reconnect: false,
+            max_requests: 32,
+            request_queue_depth: 32,
+            stream_queue_depth: 4,
+            watch_queue_depth: 8,
+            request_timeout: None,
+        }
+    }
+}
+
+impl XsTokioBuilder {
+    /// Reset the watches and transactions left over on the connection
+    /// (e.g by a previous instance or before a kexec) once opened.
+    ///
+    /// See [XsTokio::reset_watches].
+    pub fn reset_watches(mut self, reset_watches: bool) -> Self {
+        self.reset_watches = reset_watches;
+        self
+    }
+
+    /// Reopen the connection when it is lost (e.g xenstored restart or live update),
+    /// registering the watches again with their tokens.
+    ///
+    /// Requests in flight fail meanwhile, as well as the transactions as they don't
+    /// survive the connection. Xenstored fires the watches once registered again,
+    /// letting their users check for changes they may have missed.
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Maximum number of requests in flight (32 by default, at least 1).
+    ///
+    /// Further requests wait in the request queue. Requests that timed out or
+    /// whose caller gave up count until xenstored responds to them.
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = max_requests.max(1);
+        self
+    }
+
+    /// Capacity of the queue of requests waiting to be sent (32 by default, at least 1).
+    ///
+    /// Once full, callers wait for room in the queue (rather than having their
+    /// request dropped).
+    pub fn request_queue_depth(mut self, depth: usize) -> Self {
+        self.request_queue_depth = depth.max(1);
+        self
+    }
+
+    /// Capacity of the queues between the underlying task and the connection,
+    /// in messages (4 by default, at least 1).
+    pub fn stream_queue_depth(mut self, depth: usize) -> Self {
+        self.stream_queue_depth = depth.max(1);
+        self
+    }
+
+    /// Capacity of the event queue of each watch (8 by default, at least 1).
+    ///
+    /// Once full, the underlying task waits for the watch to be polled.
+    pub fn watch_queue_depth(mut self, depth: usize) -> Self {
+        self.watch_queue_depth = depth.max(1);
+        self
+    }
+
+    /// Default time to wait for the response of a request (including its time
+    /// in the request queue), after which it fails with [io::ErrorKind::TimedOut].
+    /// A request that timed out while queued is never sent.
+    ///
+    /// No timeout by default, see also [XsTokio::with_timeout].
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Try to open Xenstore interface (see [XsTokio::new]).
+    pub async fn open(self) -> io::Result<XsTokio> {
+        let xs = XsTokio {
+            handle: launch_xenstore_task(connect().await?, &self),
+            watch_queue_depth: self.watch_queue_depth,
+            timeout: self.request_timeout,
+            tx_id: 0,
+        };
+
+        if self.reset_watches {
+            xs.reset_watches().await?;
+        }
+
+        Ok(xs)
+    }
+}
 
 impl XsTokio {
     /// Try to open Xenstore interface.
     /// Attempt in order :
     ///  - `/run/xenstored/socket` (unix domain socket)
-    ///  - [crate::wire::XENBUS_DEVICE_PATH] (xenstore device)
+    ///  - [crate::protocol::XENBUS_DEVICE_PATH] (xenstore device)
     pub async fn new() -> io::Result<Self> {
-        let xsd_path =
-            env::var("XENSTORED_PATH").unwrap_or_else(|_| "/run/xenstored/socket".to_string());
+        Self::builder().open().await
+    }
+
+    /// Create a builder to open Xenstore interface with custom options.
+    pub fn builder() -> XsTokioBuilder {
+        XsTokioBuilder::default()
+    }
+
+    /// Remove all the watches and transactions of the connection.
+    ///
+    /// This also affects the watches made through this [XsTokio] (and its clones)
+    /// which will yield [None].
+    pub async fn reset_watches(&self) -> io::Result<()> {
+        self.transmit_ack(XsRequest::ResetWatches).await
+    }
+
+    /// Wait for the connection to xenstored to be closed for good, giving the cause.
+    ///
+    /// With [XsTokioBuilder::reconnect], the connection is reopened instead
+    /// (see [XsTokio::events]).
+    pub async fn closed(&self) -> io::Error {
+        // Fails if the task stopped without a cause (e.g panic).
+        let mut closed = self.handle.closed.clone();
+        closed.wait_for(Option::is_some).await.ok();
+
+        self.closed_now()
+    }
+
+    /// Error of an operation made on a closed connection.
+    fn closed_now(&self) -> io::Error {
+        match &*self.handle.closed.borrow() {
+            Some(error) => shared_error(error),
+            None => io::Error::new(ErrorKind::BrokenPipe, "Xenstore task died"),
+        }
+    }
+
+    /// Clone of this [XsTokio] whose requests time out after `timeout`
+    /// (or never if [None]), see [XsTokioBuilder::request_timeout].
+    ///
+    /// Transactions started from it use the same timeout.
+    pub fn with_timeout(&self, timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..self.clone()
+        }
+    }
 
-        // Use xenstored socket first
-        if let Ok(stream) = UnixStream::connect(xsd_path).await {
-            return Ok(Self(launch_xenstore_task(stream)));
+    /// Run `exchange` with the request timeout.
+    ///
+    /// The underlying task ignores the late response of a timed out (or dropped)
+    /// request, which keeps its slot until then (see [XsTokioBuilder::max_requests]).
+    async fn with_request_timeout<T>(
+        &self,
+        exchange: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange)
+                .await
+                .map_err(|_| io::Error::new(ErrorKind::TimedOut, "Xenstore request timed out"))?,
+            None => exchange.await,
         }
+    }
+
+    /// Subscribe to the connection events (see [XsTokioBuilder::reconnect]).
+    pub fn events(&self) -> broadcast::Receiver<XsTokioEvent> {
+        self.handle.events.subscribe()
+    }
 
-        Ok(Self(launch_xenstore_task(device::XsDevice::new().await?)))
+    fn request_message(
+        &self,
+        request: &XsRequest,
+        response_sender: oneshot::Sender<io::Result<XsMessage>>,
+    ) -> XsTokioMessage {
+        XsTokioMessage::Request(XsTokioRequest {
+            // Request id is set by the underlying task.
+            request: request.encode(0, self.tx_id),
+            response_sender,
+        })
     }
 
-    async fn transmit_request(&self, request: XsMessage) -> io::Result<XsMessage> {
-        let (response_sender, response_receiver) = oneshot::channel();
-        let req_msg_type = request.msg_type;
+    /// Send a request and get its response (which may be an error).
+    async fn transmit_message(&self, request: &XsRequest) -> io::Result<XsMessage> {
+        self.with_request_timeout(async {
+            let (response_sender, response_receiver) = oneshot::channel();
 
-        self.0
-            .send(XsTokioMessage::Request(XsTokioRequest {
-                request,
-                response_sender,
-            }))
-            .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
+            // Wait for room in the queue if needed.
+            self.handle
+                .messages
+                .send(self.request_message(request, response_sender))
+                .await
+                .map_err(|_| self.closed_now())?;
 
-        let response = response_receiver
-            .await
-            .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
+            response_receiver.await.map_err(|_| self.closed_now())?
+        })
+        .await
+    }
+
+    async fn transmit_request(&self, request: XsRequest) -> io::Result<XsResponse> {
+        let response = self
+            .transmit_message(&request)
+            .await?
+            .check_response(request.msg_type(), request.path())?;
+
+        XsResponse::decode(&response)
+    }
 
-        match response.msg_type {
-            // Response type must match request.
-            msg_type if msg_type == req_msg_type => Ok(response),
-            XsMessageType::Error => Err(response.parse_error()),
-            msg_type => Err(io::Error::new(
-                ErrorKind::InvalidData,
-                format!("Got unrelated response ({msg_type:?})"),
-            )),
+    /// Transmit a request that is only acknowledged.
+    async fn transmit_ack(&self, request: XsRequest) -> io::Result<()> {
+        match self.transmit_request(request).await? {
+            XsResponse::Ok => Ok(()),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    /// List a directory using DIRECTORY_PART, used when the listing doesn't
+    /// fit in a single message.
+    async fn directory_part(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        let mut listing = XsDirectoryPart::new(path);
+
+        loop {
+            let (generation, chunk) = match self.transmit_request(listing.request()).await? {
+                XsResponse::DirectoryPart { generation, chunk } => (generation, chunk),
+                response => return Err(response.unexpected()),
+            };
+
+            if let Some(children) = listing.push(generation, &chunk)? {
+                return Ok(children);
+            }
         }
     }
 }
 
 impl AsyncXs for XsTokio {
     async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
-        let response = self
-            .transmit_request(XsMessage::from_string(XsMessageType::Directory, 0, path))
-            .await?;
+        let request = XsRequest::Directory { path: path.into() };
+        let response = self.transmit_message(&request).await?;
 
-        Ok(response
-            .parse_payload_list()
-            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
-            // convert &str to Box<str>
-            .iter()
-            .map(|s| s.to_string().into_boxed_str())
-            .collect())
+        // E2BIG means that the directory listing is too long for DIRECTORY.
+        if response.errno() == Some(XsErrno::E2BIG) {
+            return self.directory_part(path).await;
+        }
+
+        let response = response.check_response(request.msg_type(), request.path())?;
+
+        match XsResponse::decode(&response)? {
+            XsResponse::Directory(children) => Ok(children),
+            response => Err(response.unexpected()),
+        }
     }
 
-    async fn read(&self, path: &str) -> io::Result<Box<str>> {
-        let response = self
-            .transmit_request(XsMessage::from_string(XsMessageType::Read, 0, path))
-            .await?;
+    async fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        match self
+            .transmit_request(XsRequest::Read { path: path.into() })
+            .await?
+        {
+            XsResponse::Read(value) => Ok(value),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    async fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Write {
+            path: path.into(),
+            value: data.into(),
+        })
+        .await
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Rm { path: path.into() }).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Mkdir { path: path.into() })
+            .await
+    }
 
-        Ok(response
-            .parse_payload_str()
-            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
-            .unwrap_or_default()
-            // convert &str to Box<str>
-            .to_string()
-            .into_boxed_str())
+    async fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        match self
+            .transmit_request(XsRequest::GetPerms { path: path.into() })
+            .await?
+        {
+            XsResponse::GetPerms(perms) => Ok(perms),
+            response => Err(response.unexpected()),
+        }
     }
 
-    async fn write(&self, path: &str, data: &str) -> io::Result<()> {
-        self.transmit_request(XsMessage::from_string_slice(
-            XsMessageType::Write,
-            0,
-            &[path, data],
-            false,
-        ))
-        .await?;
+    async fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetPerms {
+            path: path.into(),
+            perms: perms.to_vec(),
+        })
+        .await
+    }
+
+    async fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        match self
+            .transmit_request(XsRequest::GetDomainPath { domid })
+            .await?
+        {
+            XsResponse::GetDomainPath(path) => Ok(path),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+impl AsyncXsDomainControl for XsTokio {
+    async fn introduce(&self, domid: u16, mfn: u64, evtchn: u32) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Introduce { domid, mfn, evtchn })
+            .await
+    }
 
-        Ok(())
+    async fn release(&self, domid: u16) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Release { domid }).await
+    }
+
+    async fn is_domain_introduced(&self, domid: u16) -> io::Result<bool> {
+        match self
+            .transmit_request(XsRequest::IsDomainIntroduced { domid })
+            .await?
+        {
+            XsResponse::IsDomainIntroduced(introduced) => Ok(introduced),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    async fn resume(&self, domid: u16) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Resume { domid }).await
+    }
+
+    async fn set_target(&self, domid: u16, target: u16) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetTarget { domid, target })
+            .await
+    }
+
+    async fn get_features(&self, domid: Option<u16>) -> io::Result<u32> {
+        match self
+            .transmit_request(XsRequest::GetFeature { domid })
+            .await?
+        {
+            XsResponse::GetFeature(features) => Ok(features),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    async fn set_features(&self, domid: u16, features: u32) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetFeature { domid, features })
+            .await
+    }
+
+    async fn quota_names(&self) -> io::Result<Vec<Box<str>>> {
+        let request = XsRequest::GetQuota {
+            domid: None,
+            quota: None,
+        };
+
+        match self.transmit_request(request).await? {
+            XsResponse::GetQuota(names) => Ok(names.split_whitespace().map(Box::from).collect()),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    async fn get_quota(&self, domid: Option<u16>, quota: &str) -> io::Result<u32> {
+        let request = XsRequest::GetQuota {
+            domid,
+            quota: Some(quota.into()),
+        };
+
+        match self.transmit_request(request).await? {
+            XsResponse::GetQuota(value) => value
+                .parse()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    async fn set_quota(&self, domid: Option<u16>, quota: &str, value: u32) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetQuota {
+            domid,
+            quota: quota.into(),
+            value,
+        })
+        .await
+    }
+}
+
+impl AsyncXsControl for XsTokio {
+    async fn control<C: XsControlCommand + Sync>(&self, command: &C) -> io::Result<C::Output> {
+        match self.transmit_request(command.request()).await? {
+            XsResponse::Control(output) => command.parse(&output),
+            response => Err(response.unexpected()),
+        }
+    }
+}
+
+impl AsyncXsTransaction for XsTokio {
+    type Span = XsTokioTransaction;
+
+    async fn transaction(&self) -> io::Result<XsTokioTransaction> {
+        let tx_id = match self.transmit_request(XsRequest::TransactionStart).await? {
+            XsResponse::TransactionStart(tx_id) => tx_id,
+            response => return Err(response.unexpected()),
+        };
+
+        Ok(XsTokioTransaction {
+            xs: XsTokio {
+                tx_id,
+                ..self.clone()
+            },
+            finished: false,
+        })
+    }
+}
+
+/// Tokio Xenstore transaction.
+///
+/// Aborted on [Drop] unless commited with [AsyncXsTransactionSpan::commit].
+pub struct XsTokioTransaction {
+    xs: XsTokio,
+    finished: bool,
+}
+
+impl AsyncXs for XsTokioTransaction {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.xs.directory(path).await
+    }
+
+    async fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        self.xs.read_bytes(path).await
+    }
+
+    async fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.xs.write_bytes(path, data).await
     }
 
     async fn rm(&self, path: &str) -> io::Result<()> {
-        self.transmit_request(XsMessage::from_string(XsMessageType::Rm, 0, path))
-            .await?;
+        self.xs.rm(path).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.xs.mkdir(path).await
+    }
+
+    async fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        self.xs.get_perms(path).await
+    }
+
+    async fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        self.xs.set_perms(path, perms).await
+    }
+
+    async fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        self.xs.get_domain_path(domid).await
+    }
 
-        Ok(())
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+impl AsyncXsTransactionSpan for XsTokioTransaction {
+    async fn commit(mut self) -> io::Result<()> {
+        self.finished = true;
+
+        self.xs
+            .transmit_ack(XsRequest::TransactionEnd { commit: true })
+            .await
+    }
+}
+
+impl Drop for XsTokioTransaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            // We can't wait for the response (nor room in the queue) here, let the
+            // underlying task abort the transaction and discard its response.
+            let request = XsRequest::TransactionEnd { commit: false }.encode(0, self.xs.tx_id);
+
+            self.xs
+                .handle
+                .cleanup
+                .send(XsTokioMessage::Detached(request))
+                .ok();
+        }
     }
 }
 
 /// Tokio watch object.
 pub struct XsTokioWatch {
     event_receiver: mpsc::Receiver<Box<str>>,
-    tokio_channel: mpsc::UnboundedSender<XsTokioMessage>,
+    handle: XsTokioHandle,
     token: XsWatchToken,
 }
 
+impl XsTokioWatch {
+    /// Cause of the end of the watch (once it yielded [None]) if the connection
+    /// to xenstored has been closed, rather than the watch removed
+    /// (e.g [XsTokio::reset_watches]).
+    pub fn error(&self) -> Option<io::Error> {
+        self.handle.closed.borrow().as_ref().map(shared_error)
+    }
+}
+
 impl Stream for XsTokioWatch {
     type Item = Box<str>;
 
@@ -149,33 +591,124 @@ impl Drop for XsTokioWatch {
     fn drop(&mut self) {
         // Try to unsubscribe upstream (to not leak the watch token/state).
         // If it fails, it means that the upper backend has died.
-        self.tokio_channel
+        self.handle
+            .cleanup
             .send(XsTokioMessage::WatchUnsubscribe(self.token))
             .ok();
     }
 }
 
 impl AsyncWatch for XsTokio {
-    async fn watch(&self, path: &str) -> io::Result<impl Stream<Item = Box<str>> + 'static> {
-        let (event_sender, event_receiver) = mpsc::channel(8);
-        let (result_channel, result_receiver) = oneshot::channel();
-
-        self.0
-            .send(XsTokioMessage::WatchSubscribe {
-                path: path.to_string().into_boxed_str(),
-                event_sender,
-                result_channel,
+    // Give access to XsTokioWatch::error.
+    #[allow(refining_impl_trait)]
+    async fn watch(&self, path: &str) -> io::Result<XsTokioWatch> {
+        self.with_request_timeout(async {
+            let (event_sender, event_receiver) = mpsc::channel(self.watch_queue_depth);
+            let (result_channel, result_receiver) = oneshot::channel();
+
+            self.handle
+                .messages
+                .send(XsTokioMessage::WatchSubscribe {
+                    path: path.to_string().into_boxed_str(),
+                    event_sender,
+                    result_channel,
+                })
+                .await
+                .map_err(|_| self.closed_now())?;
+
+            let token = result_receiver.await.map_err(|_| self.closed_now())??;
+
+            Ok(XsTokioWatch {
+                event_receiver,
+                token,
+                handle: self.handle.clone(),
             })
-            .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use super::*;
+    use crate::codec::XsMessageCodec;
+
+    /// Serve `stream` like a xenstored hung on its first request until
+    /// `release`, recording the requests it gets.
+    async fn hung_xenstored(
+        stream: DuplexStream,
+        release: oneshot::Receiver<()>,
+        requests: Arc<Mutex<Vec<XsRequest>>>,
+    ) {
+        let (rx, tx) = tokio::io::split(stream);
+        let mut rx = FramedRead::new(rx, XsMessageCodec::new());
+        let mut tx = FramedWrite::new(tx, XsMessageCodec::new());
+        let mut release = Some(release);
+
+        while let Some(Ok(message)) = rx.next().await {
+            let request = XsRequest::decode(&message).unwrap();
+            requests.lock().unwrap().push(request.clone());
 
-        let token = result_receiver
+            if let Some(release) = release.take() {
+                release.await.ok();
+            }
+
+            let response = match request {
+                XsRequest::Read { .. } => XsResponse::Read(Box::default()),
+                _ => XsResponse::Ok,
+            };
+            let response = response.encode(message.msg_type, message.request_id, message.tx_id);
+
+            if tx.send(response).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_queued_request() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (release, released) = oneshot::channel();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(hung_xenstored(server, released, requests.clone()));
+
+        let builder = XsTokio::builder().max_requests(1);
+        let xs = XsTokio {
+            handle: launch_xenstore_task(client, &builder),
+            watch_queue_depth: builder.watch_queue_depth,
+            timeout: None,
+            tx_id: 0,
+        };
+
+        // Hung request, holding the only slot.
+        let hung = tokio::spawn({
+            let xs = xs.clone();
+            async move { xs.read("/hang").await }
+        });
+        while requests.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let error = xs
+            .with_timeout(Some(Duration::from_millis(100)))
+            .write("/queued", "1")
             .await
-            .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))??;
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
 
-        Ok(XsTokioWatch {
-            event_receiver,
-            token,
-            tokio_channel: self.0.clone(),
-        })
+        // Once xenstored recovers, the timed out write is not sent.
+        release.send(()).unwrap();
+        hung.await.unwrap().unwrap();
+        xs.read("/after").await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let paths: Vec<_> = requests.iter().map(XsRequest::path).collect();
+        assert_eq!(paths, [Some("/hang"), Some("/after")]);
     }
 }
@@ -1,13 +1,19 @@
 use std::{
+    convert::TryInto,
     env,
     fs::File,
     io::{self, Read, Write},
-    os::unix::net::UnixStream,
+    net::Shutdown,
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::UnixStream,
+    },
+    time::Duration,
 };
 
-use crate::wire::XENBUS_DEVICE_PATH;
+use crate::protocol::XENBUS_DEVICE_PATH;
 
-/// Raw xenstore interface (speaks [crate::wire] protocol).
+/// Raw xenstore interface (speaks [crate::protocol] protocol).
 #[derive(Debug)]
 pub enum XsUnixInterface {
     Socket(UnixStream),
@@ -31,6 +37,44 @@ impl XsUnixInterface {
                 .open(XENBUS_DEVICE_PATH)?,
         ))
     }
+
+    /// Shut down the connection (if possible), e.g after a protocol error.
+    pub fn shutdown(&self) {
+        if let XsUnixInterface::Socket(unix_stream) = self {
+            // Nothing to do if the peer is already gone.
+            unix_stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    /// Wait until there is something to read, returns false on timeout.
+    pub fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .try_into()
+            .unwrap_or(libc::c_int::MAX);
+
+        // SAFETY: pollfd is a valid array of 1 element.
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl AsRawFd for XsUnixInterface {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            XsUnixInterface::Socket(unix_stream) => unix_stream.as_raw_fd(),
+            XsUnixInterface::Device(file) => file.as_raw_fd(),
+        }
+    }
 }
 
 impl Write for XsUnixInterface {
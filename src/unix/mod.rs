@@ -4,86 +4,568 @@
 
 mod interface;
 
-use std::{cell::RefCell, io, ops::DerefMut};
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    wire::{XsMessage, XsMessageType},
-    Xs,
+    control::XsControlCommand,
+    protocol::{
+        check_payload_len, XsDirectoryPart, XsMessage, XsMessageType, XsRequest, XsResponse,
+    },
+    Xs, XsControl, XsDomainControl, XsErrno, XsPermission, XsProtocolError, XsTransaction,
+    XsTransactionSpan, XsWatch,
 };
 
+struct XsUnixState {
+    interface: interface::XsUnixInterface,
+    /// Tokens of the registered watches.
+    watches: HashSet<Box<str>>,
+    /// Watch events (path, token) received but not yet consumed.
+    pending_events: VecDeque<(Box<str>, Box<str>)>,
+    watch_count: u64,
+    /// Set once reading failed, the stream may no longer be in sync.
+    poisoned: bool,
+}
+
+impl XsUnixState {
+    fn check_poisoned(&self) -> io::Result<()> {
+        if self.poisoned {
+            return Err(XsProtocolError::Poisoned.into());
+        }
+
+        Ok(())
+    }
+
+    /// Read the next message, queuing watch events if `skip_events` is set.
+    fn read_message(&mut self, skip_events: bool) -> io::Result<XsMessage> {
+        self.check_poisoned()?;
+
+        loop {
+            let message = match XsMessage::read_from(&mut self.interface) {
+                Ok(message) => message,
+                Err(e) => {
+                    // A message may have been partially read, tear down the connection
+                    // rather than misinterpreting what follows.
+                    self.poisoned = true;
+                    self.interface.shutdown();
+                    return Err(e);
+                }
+            };
+
+            match message.msg_type {
+                XsMessageType::WatchEvent if skip_events => self.queue_event(&message)?,
+                // Can't be a response to one of our requests (e.g sent by a newer
+                // xenstored), skip it.
+                XsMessageType::Unknown(_) => {}
+                _ => return Ok(message),
+            }
+        }
+    }
+
+    /// Write a message, poisoning the connection if it fails midway.
+    fn write_message(&mut self, message: &XsMessage) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        // Nothing is written if the payload is too large.
+        check_payload_len(message.payload.len())?;
+
+        if let Err(e) = message.write_to(&mut self.interface) {
+            // A message may have been partially written, tear down the connection
+            // rather than desynchronizing the stream.
+            self.poisoned = true;
+            self.interface.shutdown();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn queue_event(&mut self, message: &XsMessage) -> io::Result<()> {
+        let (path, token) = match XsResponse::decode(message)? {
+            XsResponse::WatchEvent { path, token } => (path, token),
+            response => return Err(response.unexpected()),
+        };
+
+        // Discard events of watches we no longer have.
+        if self.watches.contains(&token) {
+            self.pending_events.push_back((path, token));
+        }
+
+        Ok(())
+    }
+}
+
 /// Unix Xenstore implementation.
-pub struct XsUnix(RefCell<interface::XsUnixInterface>);
+pub struct XsUnix {
+    state: Arc<Mutex<XsUnixState>>,
+    /// Transaction in which the requests are made (0 if none).
+    tx_id: u32,
+}
+
+/// [XsUnix] builder, to open it with custom options.
+#[derive(Clone, Debug, Default)]
+pub struct XsUnixBuilder {
+    reset_watches: bool,
+}
+
+impl XsUnixBuilder {
+    /// Reset the watches and transactions left over on the connection
+    /// (e.g by a previous instance or before a kexec) once opened.
+    ///
+    /// See [XsUnix::reset_watches].
+    pub fn reset_watches(mut self, reset_watches: bool) -> Self {
+        self.reset_watches = reset_watches;
+        self
+    }
+
+    /// Try to open Xenstore interface (see [XsUnix::new]).
+    pub fn open(self) -> io::Result<XsUnix> {
+        let xs = XsUnix {
+            state: Arc::new(Mutex::new(XsUnixState {
+                interface: interface::XsUnixInterface::new()?,
+                watches: HashSet::new(),
+                pending_events: VecDeque::new(),
+                watch_count: 0,
+                poisoned: false,
+            })),
+            tx_id: 0,
+        };
+
+        if self.reset_watches {
+            xs.reset_watches()?;
+        }
+
+        Ok(xs)
+    }
+}
 
 impl XsUnix {
     /// Try to open Xenstore interface.
     /// Attempt in order :
     ///  - `/run/xenstored/socket` (unix domain socket)
-    ///  - [crate::wire::XENBUS_DEVICE_PATH] (xenstore device)
+    ///  - [crate::protocol::XENBUS_DEVICE_PATH] (xenstore device)
     pub fn new() -> io::Result<Self> {
-        Ok(Self(RefCell::new(interface::XsUnixInterface::new()?)))
+        Self::builder().open()
     }
 
-    fn transmit_request(&self, request: XsMessage) -> io::Result<XsMessage> {
-        let mut writer = self.0.borrow_mut();
-        request.write_to(writer.deref_mut())?;
+    /// Create a builder to open Xenstore interface with custom options.
+    pub fn builder() -> XsUnixBuilder {
+        XsUnixBuilder::default()
+    }
 
-        let response = XsMessage::read_from(writer.deref_mut())?;
+    /// Remove all the watches and transactions of the connection.
+    ///
+    /// This also affects the watches made through this [XsUnix] which will
+    /// no longer receive events and fail instead.
+    pub fn reset_watches(&self) -> io::Result<()> {
+        self.transmit_ack(XsRequest::ResetWatches)?;
 
-        match response.msg_type {
-            // Response type must match request.
-            msg_type if msg_type == request.msg_type => Ok(response),
-            XsMessageType::Error => Err(response.parse_error()),
-            msg_type => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Got unrelated response ({msg_type:?})"),
-            )),
+        let mut state = self.state();
+        state.watches.clear();
+        state.pending_events.clear();
+
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, XsUnixState> {
+        // A poisoned lock only means that another thread panicked while using it,
+        // the interface itself is still usable.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send a request and get its response (which may be an error).
+    fn transmit_message(&self, request: &XsRequest) -> io::Result<XsMessage> {
+        let request = request.encode(0, self.tx_id);
+
+        let mut state = self.state();
+        state.write_message(&request)?;
+
+        // Watch events may come before the response, keep them for later.
+        state.read_message(true)
+    }
+
+    fn transmit_request(&self, request: XsRequest) -> io::Result<XsResponse> {
+        let response = self
+            .transmit_message(&request)?
+            .check_response(request.msg_type(), request.path())?;
+
+        XsResponse::decode(&response)
+    }
+
+    /// Transmit a request that is only acknowledged.
+    fn transmit_ack(&self, request: XsRequest) -> io::Result<()> {
+        match self.transmit_request(request)? {
+            XsResponse::Ok => Ok(()),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    /// List a directory using DIRECTORY_PART, used when the listing doesn't
+    /// fit in a single message.
+    fn directory_part(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        let mut listing = XsDirectoryPart::new(path);
+
+        loop {
+            let (generation, chunk) = match self.transmit_request(listing.request())? {
+                XsResponse::DirectoryPart { generation, chunk } => (generation, chunk),
+                response => return Err(response.unexpected()),
+            };
+
+            if let Some(children) = listing.push(generation, &chunk)? {
+                return Ok(children);
+            }
         }
     }
 }
 
 impl Xs for XsUnix {
     fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
-        // TODO: If we receive E2BIG, it means that the directory listing is too long,
-        //       and that we should use DIRECTORY_PART.
-        let response =
-            self.transmit_request(XsMessage::from_string(XsMessageType::Directory, 0, path))?;
-
-        Ok(response
-            .parse_payload_list()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
-            // convert &str to Box<str>
-            .iter()
-            .map(|s| s.to_string().into_boxed_str())
-            .collect())
-    }
-
-    fn read(&self, path: &str) -> io::Result<Box<str>> {
-        let response =
-            self.transmit_request(XsMessage::from_string(XsMessageType::Read, 0, path))?;
-
-        Ok(response
-            .parse_payload_str()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
-            .unwrap_or_default()
-            // convert &str to Box<str>
-            .to_string()
-            .into_boxed_str())
-    }
-
-    fn write(&self, path: &str, data: &str) -> io::Result<()> {
-        self.transmit_request(XsMessage::from_string_slice(
-            XsMessageType::Write,
-            0,
-            &[path, data],
-            false,
-        ))?;
+        let request = XsRequest::Directory { path: path.into() };
+        let response = self.transmit_message(&request)?;
 
-        Ok(())
+        // E2BIG means that the directory listing is too long for DIRECTORY.
+        if response.errno() == Some(XsErrno::E2BIG) {
+            return self.directory_part(path);
+        }
+
+        let response = response.check_response(request.msg_type(), request.path())?;
+
+        match XsResponse::decode(&response)? {
+            XsResponse::Directory(children) => Ok(children),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        match self.transmit_request(XsRequest::Read { path: path.into() })? {
+            XsResponse::Read(value) => Ok(value),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Write {
+            path: path.into(),
+            value: data.into(),
+        })
     }
 
     fn rm(&self, path: &str) -> io::Result<()> {
-        self.transmit_request(XsMessage::from_string(XsMessageType::Rm, 0, path))?;
+        self.transmit_ack(XsRequest::Rm { path: path.into() })
+    }
 
-        Ok(())
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Mkdir { path: path.into() })
+    }
+
+    fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        match self.transmit_request(XsRequest::GetPerms { path: path.into() })? {
+            XsResponse::GetPerms(perms) => Ok(perms),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetPerms {
+            path: path.into(),
+            perms: perms.to_vec(),
+        })
+    }
+
+    fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        match self.transmit_request(XsRequest::GetDomainPath { domid })? {
+            XsResponse::GetDomainPath(path) => Ok(path),
+            response => Err(response.unexpected()),
+        }
+    }
+}
+
+impl XsDomainControl for XsUnix {
+    fn introduce(&self, domid: u16, mfn: u64, evtchn: u32) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Introduce { domid, mfn, evtchn })
+    }
+
+    fn release(&self, domid: u16) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Release { domid })
+    }
+
+    fn is_domain_introduced(&self, domid: u16) -> io::Result<bool> {
+        match self.transmit_request(XsRequest::IsDomainIntroduced { domid })? {
+            XsResponse::IsDomainIntroduced(introduced) => Ok(introduced),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn resume(&self, domid: u16) -> io::Result<()> {
+        self.transmit_ack(XsRequest::Resume { domid })
+    }
+
+    fn set_target(&self, domid: u16, target: u16) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetTarget { domid, target })
+    }
+
+    fn get_features(&self, domid: Option<u16>) -> io::Result<u32> {
+        match self.transmit_request(XsRequest::GetFeature { domid })? {
+            XsResponse::GetFeature(features) => Ok(features),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn set_features(&self, domid: u16, features: u32) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetFeature { domid, features })
+    }
+
+    fn quota_names(&self) -> io::Result<Vec<Box<str>>> {
+        let request = XsRequest::GetQuota {
+            domid: None,
+            quota: None,
+        };
+
+        match self.transmit_request(request)? {
+            XsResponse::GetQuota(names) => Ok(names.split_whitespace().map(Box::from).collect()),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn get_quota(&self, domid: Option<u16>, quota: &str) -> io::Result<u32> {
+        let request = XsRequest::GetQuota {
+            domid,
+            quota: Some(quota.into()),
+        };
+
+        match self.transmit_request(request)? {
+            XsResponse::GetQuota(value) => value
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn set_quota(&self, domid: Option<u16>, quota: &str, value: u32) -> io::Result<()> {
+        self.transmit_ack(XsRequest::SetQuota {
+            domid,
+            quota: quota.into(),
+            value,
+        })
+    }
+}
+
+impl XsControl for XsUnix {
+    fn control<C: XsControlCommand>(&self, command: &C) -> io::Result<C::Output> {
+        match self.transmit_request(command.request())? {
+            XsResponse::Control(output) => command.parse(&output),
+            response => Err(response.unexpected()),
+        }
+    }
+}
+
+impl XsTransaction for XsUnix {
+    type Span = XsUnixTransaction;
+
+    fn transaction(&self) -> io::Result<XsUnixTransaction> {
+        let tx_id = match self.transmit_request(XsRequest::TransactionStart)? {
+            XsResponse::TransactionStart(tx_id) => tx_id,
+            response => return Err(response.unexpected()),
+        };
+
+        Ok(XsUnixTransaction {
+            xs: XsUnix {
+                state: self.state.clone(),
+                tx_id,
+            },
+            finished: false,
+        })
+    }
+}
+
+/// Unix Xenstore transaction.
+///
+/// Aborted on [Drop] unless commited with [XsTransactionSpan::commit].
+pub struct XsUnixTransaction {
+    xs: XsUnix,
+    finished: bool,
+}
+
+impl XsUnixTransaction {
+    fn end(&mut self, commit: bool) -> io::Result<()> {
+        self.finished = true;
+
+        self.xs.transmit_ack(XsRequest::TransactionEnd { commit })
+    }
+}
+
+impl Xs for XsUnixTransaction {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.xs.directory(path)
+    }
+
+    fn read_bytes(&self, path: &str) -> io::Result<Box<[u8]>> {
+        self.xs.read_bytes(path)
+    }
+
+    fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.xs.write_bytes(path, data)
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        self.xs.rm(path)
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.xs.mkdir(path)
+    }
+
+    fn get_perms(&self, path: &str) -> io::Result<Vec<XsPermission>> {
+        self.xs.get_perms(path)
+    }
+
+    fn set_perms(&self, path: &str, perms: &[XsPermission]) -> io::Result<()> {
+        self.xs.set_perms(path, perms)
+    }
+
+    fn get_domain_path(&self, domid: u16) -> io::Result<Box<str>> {
+        self.xs.get_domain_path(domid)
+    }
+}
+
+impl XsTransactionSpan for XsUnixTransaction {
+    fn commit(mut self) -> io::Result<()> {
+        self.end(true)
+    }
+}
+
+impl Drop for XsUnixTransaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            // Nothing we can do if abort fails, the transaction will be
+            // discarded by xenstored anyway once the connection is closed.
+            self.end(false).ok();
+        }
+    }
+}
+
+impl XsWatch for XsUnix {
+    type Watch = XsUnixWatch;
+
+    fn watch(&self, path: &str) -> io::Result<XsUnixWatch> {
+        let token: Box<str> = {
+            let mut state = self.state();
+            state.watch_count += 1;
+
+            let token: Box<str> = format!("xs-unix-{}", state.watch_count).into();
+            state.watches.insert(token.clone());
+            token
+        };
+
+        let watch = XsUnixWatch {
+            xs: XsUnix {
+                state: self.state.clone(),
+                tx_id: 0,
+            },
+            path: path.into(),
+            token,
+        };
+
+        // On failure, watch is dropped and its token unregistered.
+        self.transmit_ack(XsRequest::Watch {
+            path: watch.path.clone(),
+            token: watch.token.clone(),
+        })?;
+
+        Ok(watch)
+    }
+}
+
+/// Unix watch object.
+///
+/// Yields paths of updated nodes/subnodes (starting with `path` itself).
+///
+/// Waiting for an event blocks the other operations made on the same [XsUnix],
+/// consider using a dedicated [XsUnix] for watching if that is a concern.
+pub struct XsUnixWatch {
+    xs: XsUnix,
+    path: Box<str>,
+    token: Box<str>,
+}
+
+impl XsUnixWatch {
+    /// Wait for the next event.
+    ///
+    /// Returns `Ok(None)` if there was no event within `timeout`, waits forever
+    /// if `timeout` is [None].
+    /// Fails once the watch has been removed (see [XsUnix::reset_watches]).
+    pub fn next_timeout(&mut self, timeout: Option<Duration>) -> io::Result<Option<Box<str>>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.xs.state();
+
+        loop {
+            // Watch has been removed (e.g reset), no event will ever come.
+            if !state.watches.contains(&self.token) {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Watch has been removed",
+                ));
+            }
+
+            if let Some(index) = state
+                .pending_events
+                .iter()
+                .position(|(_, token)| *token == self.token)
+            {
+                return Ok(state.pending_events.remove(index).map(|(path, _)| path));
+            }
+
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+
+                match state.interface.wait_readable(remaining) {
+                    Ok(true) => {}
+                    Ok(false) => return Ok(None),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let message = state.read_message(false)?;
+
+            if message.msg_type != XsMessageType::WatchEvent {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Got unsolicited response ({:?})", message.msg_type),
+                ));
+            }
+
+            state.queue_event(&message)?;
+        }
+    }
+}
+
+impl Iterator for XsUnixWatch {
+    type Item = io::Result<Box<str>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_timeout(None).transpose()
+    }
+}
+
+impl Drop for XsUnixWatch {
+    fn drop(&mut self) {
+        // Nothing we can do if unwatch fails (e.g dead interface).
+        self.xs
+            .transmit_ack(XsRequest::Unwatch {
+                path: self.path.clone(),
+                token: self.token.clone(),
+            })
+            .ok();
+
+        let mut state = self.xs.state();
+        state.watches.remove(&self.token);
+        state
+            .pending_events
+            .retain(|(_, token)| *token != self.token);
     }
 }
@@ -0,0 +1,226 @@
+//! Drive the clients against [XsServer] through `XENSTORED_PATH`.
+
+use std::{env, fs, io, sync::OnceLock, thread, time::Duration};
+
+use futures::StreamExt;
+use tokio::net::UnixListener;
+use xenstore_rs::{
+    memory::XsMemoryStore, server::XsServer, tokio::XsTokio, unix::XsUnix, AsyncWatch, AsyncXs,
+    AsyncXsTransaction, AsyncXsTransactionSpan, Xs, XsErrno, XsError, XsTransaction,
+    XsTransactionSpan, XsWatch,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start the server (once for all the tests) and point the clients to it.
+fn start_server() {
+    static SERVER: OnceLock<()> = OnceLock::new();
+
+    SERVER.get_or_init(|| {
+        let path = env::temp_dir().join(format!("xenstore-rs-server-{}.sock", std::process::id()));
+        fs::remove_file(&path).ok();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = {
+            let _guard = runtime.enter();
+            UnixListener::bind(&path).unwrap()
+        };
+
+        thread::spawn(move || {
+            let server = XsServer::new(XsMemoryStore::new());
+            runtime.block_on(server.serve(listener, 0)).unwrap();
+        });
+
+        env::set_var("XENSTORED_PATH", &path);
+    });
+}
+
+fn errno(error: &io::Error) -> Option<XsErrno> {
+    XsError::from_io(error)?.errno().cloned()
+}
+
+#[test]
+fn unix_read_write() {
+    start_server();
+    let xs = XsUnix::new().unwrap();
+
+    xs.write("/unix/rw/a", "1").unwrap();
+    xs.write_bytes("/unix/rw/b", b"\xff").unwrap();
+    assert_eq!(&*xs.read("/unix/rw/a").unwrap(), "1");
+    assert_eq!(&*xs.read_bytes("/unix/rw/b").unwrap(), b"\xff");
+    assert_eq!(
+        xs.directory("/unix/rw").unwrap(),
+        vec!["a".into(), "b".into()]
+    );
+
+    xs.rm("/unix/rw/a").unwrap();
+    let error = xs.read("/unix/rw/a").unwrap_err();
+    assert_eq!(errno(&error), Some(XsErrno::ENOENT));
+}
+
+#[test]
+fn unix_watch() {
+    start_server();
+    let xs = XsUnix::new().unwrap();
+
+    let mut watch = xs.watch("/unix/watch").unwrap();
+    assert_eq!(
+        watch.next_timeout(Some(TIMEOUT)).unwrap().as_deref(),
+        Some("/unix/watch")
+    );
+
+    xs.write("/unix/watch/a", "1").unwrap();
+    assert_eq!(
+        watch.next_timeout(Some(TIMEOUT)).unwrap().as_deref(),
+        Some("/unix/watch/a")
+    );
+}
+
+#[test]
+fn unix_watch_reset() {
+    start_server();
+    let xs = XsUnix::new().unwrap();
+
+    let mut watch = xs.watch("/unix/reset").unwrap();
+    assert_eq!(
+        watch.next_timeout(Some(TIMEOUT)).unwrap().as_deref(),
+        Some("/unix/reset")
+    );
+
+    xs.reset_watches().unwrap();
+    assert!(watch.next_timeout(None).is_err());
+}
+
+#[test]
+fn unix_transaction() {
+    start_server();
+    let xs = XsUnix::new().unwrap();
+    xs.write("/unix/tx/a", "1").unwrap();
+
+    let transaction = xs.transaction().unwrap();
+    transaction.write("/unix/tx/a", "2").unwrap();
+    assert_eq!(&*xs.read("/unix/tx/a").unwrap(), "1");
+    transaction.commit().unwrap();
+    assert_eq!(&*xs.read("/unix/tx/a").unwrap(), "2");
+
+    // Conflicting transaction.
+    let transaction = xs.transaction().unwrap();
+    transaction.read("/unix/tx/a").unwrap();
+    xs.write("/unix/tx/a", "3").unwrap();
+    transaction.write("/unix/tx/a", "4").unwrap();
+    let error = transaction.commit().unwrap_err();
+    assert_eq!(errno(&error), Some(XsErrno::EAGAIN));
+    assert_eq!(&*xs.read("/unix/tx/a").unwrap(), "3");
+}
+
+#[tokio::test]
+async fn tokio_read_write() {
+    start_server();
+    let xs = XsTokio::new().await.unwrap();
+
+    xs.write("/tokio/rw/a", "1").await.unwrap();
+    xs.write_bytes("/tokio/rw/b", b"\xff").await.unwrap();
+    assert_eq!(&*xs.read("/tokio/rw/a").await.unwrap(), "1");
+    assert_eq!(&*xs.read_bytes("/tokio/rw/b").await.unwrap(), b"\xff");
+    assert_eq!(
+        xs.directory("/tokio/rw").await.unwrap(),
+        vec!["a".into(), "b".into()]
+    );
+
+    xs.rm("/tokio/rw/a").await.unwrap();
+    let error = xs.read("/tokio/rw/a").await.unwrap_err();
+    assert_eq!(errno(&error), Some(XsErrno::ENOENT));
+}
+
+#[tokio::test]
+async fn tokio_watch() {
+    start_server();
+    let xs = XsTokio::new().await.unwrap();
+
+    let mut watch = xs.watch("/tokio/watch").await.unwrap();
+    let event = tokio::time::timeout(TIMEOUT, watch.next()).await.unwrap();
+    assert_eq!(event.as_deref(), Some("/tokio/watch"));
+
+    xs.write("/tokio/watch/a", "1").await.unwrap();
+    let event = tokio::time::timeout(TIMEOUT, watch.next()).await.unwrap();
+    assert_eq!(event.as_deref(), Some("/tokio/watch/a"));
+}
+
+#[tokio::test]
+async fn tokio_transaction() {
+    start_server();
+    let xs = XsTokio::new().await.unwrap();
+    xs.write("/tokio/tx/a", "1").await.unwrap();
+
+    let transaction = xs.transaction().await.unwrap();
+    transaction.write("/tokio/tx/a", "2").await.unwrap();
+    assert_eq!(&*xs.read("/tokio/tx/a").await.unwrap(), "1");
+    transaction.commit().await.unwrap();
+    assert_eq!(&*xs.read("/tokio/tx/a").await.unwrap(), "2");
+
+    // Conflicting transaction.
+    let transaction = xs.transaction().await.unwrap();
+    transaction.read("/tokio/tx/a").await.unwrap();
+    xs.write("/tokio/tx/a", "3").await.unwrap();
+    transaction.write("/tokio/tx/a", "4").await.unwrap();
+    let error = transaction.commit().await.unwrap_err();
+    assert_eq!(errno(&error), Some(XsErrno::EAGAIN));
+    assert_eq!(&*xs.read("/tokio/tx/a").await.unwrap(), "3");
+}
+
+#[tokio::test]
+async fn tokio_home_path() {
+    start_server();
+    let xs = XsTokio::new().await.unwrap();
+
+    xs.write("/local/domain/0/domid", "0").await.unwrap();
+    assert_eq!(xs.get_domid().await.unwrap(), 0);
+    assert_eq!(&*xs.get_home_path().await.unwrap(), "/local/domain/0");
+}
+
+#[tokio::test]
+async fn tokio_transact() {
+    start_server();
+    let xs = XsTokio::new().await.unwrap();
+    xs.write("/tokio/transact/a", "1").await.unwrap();
+
+    let task = tokio::spawn({
+        let xs = xs.clone();
+
+        async move {
+            let mut attempts = 0;
+
+            let value = xs
+                .transact(|span| {
+                    attempts += 1;
+                    let first = attempts == 1;
+                    let xs = xs.clone();
+
+                    Box::pin(async move {
+                        let value = span.read("/tokio/transact/a").await?;
+
+                        // Conflict on the first attempt.
+                        if first {
+                            xs.write("/tokio/transact/a", "2").await?;
+                        }
+
+                        span.write("/tokio/transact/a", &format!("{value}+"))
+                            .await?;
+                        Ok(value)
+                    })
+                })
+                .await
+                .unwrap();
+
+            (attempts, value)
+        }
+    });
+
+    let (attempts, value) = task.await.unwrap();
+    assert_eq!(attempts, 2);
+    assert_eq!(&*value, "2");
+    assert_eq!(&*xs.read("/tokio/transact/a").await.unwrap(), "2+");
+}